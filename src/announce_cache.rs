@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bittorrent::{InfoHash, Peer},
+    data_dir::DataDir,
+};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The outcome of the last successful announce to a tracker.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CachedAnnounce {
+    /// Unix timestamp of the announce
+    pub announced_at: u64,
+    /// Seconds the tracker asked us to wait before announcing again
+    pub min_interval: u64,
+    pub peers: Vec<Peer>,
+}
+
+impl CachedAnnounce {
+    /// How long until the tracker may be announced to again, `None` once
+    /// that time has passed.
+    pub fn remaining(&self, now: u64) -> Option<Duration> {
+        let next = self.announced_at.saturating_add(self.min_interval);
+
+        if now < next {
+            Some(Duration::from_secs(next - now))
+        } else {
+            None
+        }
+    }
+
+    fn to_line(&self, tracker: &str) -> String {
+        format!(
+            "{}\t{}\t{}\t{}",
+            tracker,
+            self.announced_at,
+            self.min_interval,
+            self.peers
+                .iter()
+                .map(|p| p.hostname())
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    }
+
+    fn from_line(line: &str) -> Option<(String, Self)> {
+        let mut fields = line.split('\t');
+        let tracker = fields.next()?.to_string();
+        let announced_at = fields.next()?.parse().ok()?;
+        let min_interval = fields.next()?.parse().ok()?;
+        let peers = fields
+            .next()?
+            .split_whitespace()
+            .map(|hostname| {
+                let (ip, port) = hostname.rsplit_once(':')?;
+                Some(Peer {
+                    id: None,
+                    ip: ip.to_string(),
+                    port: port.parse().ok()?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some((
+            tracker,
+            CachedAnnounce {
+                announced_at,
+                min_interval,
+                peers,
+            },
+        ))
+    }
+}
+
+/// Remembers, per tracker, when a torrent was last announced and which peers
+/// came back, so a quick restart reuses them instead of announcing again
+/// before the tracker's min interval is up.
+#[derive(Debug)]
+pub struct AnnounceCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, CachedAnnounce>>,
+}
+
+impl AnnounceCache {
+    /// Loads the cache of `info_hash` from the data dir. A missing or
+    /// unreadable cache is an empty one.
+    pub fn load(data_dir: &DataDir, info_hash: &InfoHash) -> Self {
+        let path = data_dir
+            .path()
+            .join("announces")
+            .join(hex::encode(info_hash.as_bytes()));
+
+        let entries = std::fs::read_to_string(&path)
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(CachedAnnounce::from_line)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        AnnounceCache {
+            path,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// The cached announce of `tracker` if it is still within its min
+    /// interval, with the time left until it may be announced to again.
+    pub fn fresh(&self, tracker: &str) -> Option<(Vec<Peer>, Duration)> {
+        let entries = self.entries.lock().expect("announce cache lock poisoned");
+        let cached = entries.get(tracker)?;
+
+        cached
+            .remaining(now())
+            .map(|remaining| (cached.peers.clone(), remaining))
+    }
+
+    /// The peers `tracker` returned the last time it answered, however long
+    /// ago that was.
+    pub fn last_known(&self, tracker: &str) -> Option<Vec<Peer>> {
+        let entries = self.entries.lock().expect("announce cache lock poisoned");
+
+        entries.get(tracker).map(|cached| cached.peers.clone())
+    }
+
+    pub fn store(&self, tracker: &str, min_interval: u64, peers: Vec<Peer>) {
+        let mut entries = self.entries.lock().expect("announce cache lock poisoned");
+
+        entries.insert(
+            tracker.to_string(),
+            CachedAnnounce {
+                announced_at: now(),
+                min_interval,
+                peers,
+            },
+        );
+
+        let contents = entries
+            .iter()
+            .map(|(t, cached)| format!("{}\n", cached.to_line(t)))
+            .collect::<String>();
+
+        // Write then rename, so a crash never leaves a half written cache
+        let tmp_path = self.path.with_extension("tmp");
+        let saved = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&tmp_path, contents))
+            .and_then(|_| std::fs::rename(&tmp_path, &self.path));
+
+        if let Err(e) = saved {
+            eprintln!(
+                "Could not save announce cache {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[test]
+fn test_cached_announce_freshness() {
+    let cached = CachedAnnounce {
+        announced_at: 1000,
+        min_interval: 60,
+        peers: vec![],
+    };
+
+    assert_eq!(cached.remaining(1000), Some(Duration::from_secs(60)));
+    assert_eq!(cached.remaining(1059), Some(Duration::from_secs(1)));
+    assert_eq!(cached.remaining(1060), None);
+}
+
+#[test]
+fn test_announce_cache_round_trip() {
+    let path = crate::util::temp_path("announce-cache-test");
+    let data_dir = DataDir::open(&path).unwrap();
+    let info_hash = InfoHash::for_tests();
+    let peers = vec![
+        Peer {
+            id: None,
+            ip: "10.0.0.1".to_string(),
+            port: 6881,
+        },
+        Peer {
+            id: None,
+            ip: "::1".to_string(),
+            port: 51413,
+        },
+    ];
+
+    AnnounceCache::load(&data_dir, &info_hash).store("http://t/announce", 600, peers.clone());
+    let reloaded = AnnounceCache::load(&data_dir, &info_hash);
+
+    std::fs::remove_dir_all(&path).unwrap();
+
+    let (cached_peers, remaining) = reloaded.fresh("http://t/announce").unwrap();
+    assert_eq!(cached_peers, peers);
+    assert!(remaining <= Duration::from_secs(600));
+    assert_eq!(reloaded.fresh("http://other/announce"), None);
+}
+
+#[test]
+fn test_stale_announce_is_still_last_known() {
+    let path = crate::util::temp_path("announce-stale-test");
+    let data_dir = DataDir::open(&path).unwrap();
+    let cache = AnnounceCache::load(&data_dir, &InfoHash::for_tests());
+    let peers = vec![Peer {
+        id: None,
+        ip: "10.0.0.1".to_string(),
+        port: 6881,
+    }];
+
+    cache.store("http://t/announce", 0, peers.clone());
+
+    std::fs::remove_dir_all(&path).unwrap();
+
+    assert_eq!(cache.fresh("http://t/announce"), None);
+    assert_eq!(cache.last_known("http://t/announce"), Some(peers));
+    assert_eq!(cache.last_known("http://other/announce"), None);
+}
@@ -1,17 +1,22 @@
 use std::{
+    collections::HashSet,
     fmt::{Display, write},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
 };
 
-use bendy::decoding::{FromBencode, Object};
+use bendy::decoding::{Decoder, FromBencode, Object};
 use rand::RngCore;
 use reqwest::Url;
 use sha1_checked::Sha1;
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
 
-use crate::util::url_encode_byte_string;
+use crate::util::{info, url_encode_byte_string};
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct PeerId(Vec<u8>);
 
 impl Display for PeerId {
@@ -22,9 +27,19 @@ impl Display for PeerId {
 
 impl PeerId {
     pub fn new() -> Self {
+        PeerId::from_rng(&mut rand::thread_rng())
+    }
+
+    /// The same id for the same `seed`, so tests can rely on it.
+    #[cfg(test)]
+    pub fn from_seed(seed: u64) -> Self {
+        PeerId::from_rng(&mut <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(seed))
+    }
+
+    fn from_rng(rng: &mut impl RngCore) -> Self {
         let mut peer_id: Vec<u8> = [b'-', b'L', b'T', b'0', b'0', b'1', b'0', b'-'].to_vec();
         let mut rand_peer_id: [u8; 12] = [0; 12];
-        rand::thread_rng().fill_bytes(&mut rand_peer_id);
+        rng.fill_bytes(&mut rand_peer_id);
 
         peer_id.append(&mut rand_peer_id.to_vec());
 
@@ -52,7 +67,7 @@ impl FromBencode for PeerId {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct InfoHash(Vec<u8>);
 
 impl Display for InfoHash {
@@ -66,14 +81,24 @@ impl InfoHash {
         InfoHash(Sha1::try_digest(info_bytes).hash().to_vec())
     }
 
+    /// The info hash of a small info dict, shared by tests.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        InfoHash::from_info_bytes(b"d4:name4:teste")
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         &self.0.as_slice()
     }
 }
 
+#[derive(Debug)]
 pub enum PeerConnectionError {
     InvalidUrl(String),
     SocketUnavailable(String),
+    /// The peer serves another torrent on that address, so it is no use
+    /// trying it again for this one
+    InfoHashMismatch(String),
     Other(String),
 }
 
@@ -84,11 +109,124 @@ impl Display for PeerConnectionError {
         match self {
             InvalidUrl(e) => write!(f, "PeerConnectionError::InvalidUrl: {}", e),
             SocketUnavailable(e) => write!(f, "PeerConnectionError::SocketUnavailable: {}", e),
+            InfoHashMismatch(e) => write!(f, "PeerConnectionError::InfoHashMismatch: {}", e),
             Other(e) => write!(f, "PeerConnectionError::Other: {}", e),
         }
     }
 }
 
+/// How long a peer that accepted the connection gets to send its handshake.
+#[cfg_attr(
+    not(test),
+    allow(dead_code, reason = "downloads don't connect to peers yet")
+)]
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[cfg_attr(
+    not(test),
+    allow(dead_code, reason = "downloads don't connect to peers yet")
+)]
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// Whether we run a DHT node to advertise in handshakes.
+#[cfg_attr(
+    not(test),
+    allow(dead_code, reason = "downloads don't connect to peers yet")
+)]
+const DHT_SUPPORTED: bool = false;
+
+/// The first message each side of a peer connection sends: pstrlen, pstr,
+/// 8 reserved bytes flagging supported extensions, info hash and peer id.
+#[cfg_attr(
+    not(test),
+    allow(dead_code, reason = "downloads don't connect to peers yet")
+)]
+#[derive(Debug, PartialEq, Clone)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+}
+
+#[cfg_attr(
+    not(test),
+    allow(dead_code, reason = "downloads don't connect to peers yet")
+)]
+impl Handshake {
+    pub const LENGTH: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+    /// A handshake advertising no extensions.
+    pub fn new(info_hash: InfoHash, peer_id: PeerId) -> Self {
+        Handshake {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Our handshake for a torrent. Private torrents never advertise DHT
+    /// support, as their trackers forbid finding peers through it.
+    pub fn for_torrent(info_hash: InfoHash, peer_id: PeerId, private: bool) -> Self {
+        let mut handshake = Handshake::new(info_hash, peer_id);
+        if DHT_SUPPORTED && !private {
+            handshake.reserved[7] |= 0x01;
+        }
+
+        handshake
+    }
+
+    /// BEP 5, the peer runs a DHT node
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
+    /// BEP 6, the fast extension
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    /// BEP 10, the extension protocol
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(Self::LENGTH);
+        buffer.push(PROTOCOL.len() as u8);
+        buffer.extend_from_slice(PROTOCOL);
+        buffer.extend_from_slice(&self.reserved);
+        buffer.extend_from_slice(self.info_hash.as_bytes());
+        buffer.extend_from_slice(self.peer_id.as_bytes());
+
+        buffer
+    }
+
+    pub fn decode(b: &[u8]) -> Result<Self, PeerConnectionError> {
+        if b.len() != Self::LENGTH {
+            return Err(PeerConnectionError::Other(format!(
+                "handshake is {} bytes long instead of {}",
+                b.len(),
+                Self::LENGTH
+            )));
+        }
+
+        if b[0] as usize != PROTOCOL.len() || &b[1..20] != PROTOCOL {
+            return Err(PeerConnectionError::Other(
+                "peer does not speak the BitTorrent protocol".to_string(),
+            ));
+        }
+
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&b[20..28]);
+
+        Ok(Handshake {
+            reserved,
+            info_hash: InfoHash(b[28..48].to_vec()),
+            peer_id: PeerId::from_bytes(&b[48..68]),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct PeerConnection {
     pub hostname: String,
@@ -100,10 +238,12 @@ pub struct PeerConnection {
 }
 
 impl PeerConnection {
+    #[allow(dead_code, reason = "downloads don't connect to peers yet")]
     pub async fn connect(
         url: &String,
         info_hash: &InfoHash,
         peer_id: &PeerId,
+        private: bool,
     ) -> Result<Self, PeerConnectionError> {
         let mut conn = PeerConnection {
             hostname: Url::from_str(url.as_str())
@@ -120,24 +260,43 @@ impl PeerConnection {
             they_interested: false,
         };
 
-        conn.handshake(&info_hash, &peer_id).await?;
+        conn.handshake(&info_hash, &peer_id, private).await?;
 
         Ok(conn)
     }
 
+    #[cfg_attr(
+        not(test),
+        allow(dead_code, reason = "downloads don't connect to peers yet")
+    )]
     pub async fn handshake(
         &mut self,
         info_hash: &InfoHash,
         peer_id: &PeerId,
+        private: bool,
+    ) -> Result<(), PeerConnectionError> {
+        self.handshake_within(info_hash, peer_id, private, HANDSHAKE_TIMEOUT)
+            .await
+    }
+
+    /// Sends our handshake and waits up to `timeout` for the peer's. A peer
+    /// can accept the TCP connection and then never answer, so this is
+    /// separate from the connect itself.
+    #[cfg_attr(
+        not(test),
+        allow(dead_code, reason = "downloads don't connect to peers yet")
+    )]
+    async fn handshake_within(
+        &mut self,
+        info_hash: &InfoHash,
+        peer_id: &PeerId,
+        private: bool,
+        timeout: std::time::Duration,
     ) -> Result<(), PeerConnectionError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        std::io::Write::write(&mut buffer, &[0x13]).unwrap();
-        std::io::Write::write(&mut buffer, b"BitTorrent protocol" as &[u8]).unwrap();
-        std::io::Write::write(&mut buffer, info_hash.as_bytes()).unwrap();
-        std::io::Write::write(&mut buffer, peer_id.as_bytes()).unwrap();
+        let ours = Handshake::for_torrent(info_hash.clone(), peer_id.clone(), private);
 
         self.socket
-            .write_all(buffer.as_slice())
+            .write_all(&ours.encode())
             .await
             .map_err(|e| PeerConnectionError::SocketUnavailable(e.to_string()))?;
 
@@ -145,6 +304,27 @@ impl PeerConnection {
             .flush()
             .await
             .map_err(|e| PeerConnectionError::SocketUnavailable(e.to_string()))?;
+
+        let mut reply = [0u8; Handshake::LENGTH];
+        tokio::time::timeout(timeout, self.socket.read_exact(&mut reply))
+            .await
+            .map_err(|_| {
+                PeerConnectionError::SocketUnavailable(format!(
+                    "no handshake from peer within {:?}",
+                    timeout
+                ))
+            })?
+            .map_err(|e| PeerConnectionError::SocketUnavailable(e.to_string()))?;
+
+        let theirs = Handshake::decode(&reply)?;
+
+        if &theirs.info_hash != info_hash {
+            return Err(PeerConnectionError::InfoHashMismatch(format!(
+                "peer answered for {}",
+                hex::encode(theirs.info_hash.as_bytes())
+            )));
+        }
+
         Ok(())
     }
 }
@@ -161,9 +341,56 @@ impl DownloadProgress {
     pub fn finished(&self) -> bool {
         self.bytes_downloaded == self.bytes_total
     }
+
+    /// How much is downloaded, from 0 to 100. Nothing to download counts as
+    /// done, like in [`DownloadProgress::finished`].
+    pub fn percent(&self) -> f64 {
+        if self.bytes_total == 0 {
+            return 100.0;
+        }
+
+        self.bytes_downloaded as f64 * 100.0 / self.bytes_total as f64
+    }
 }
 
-#[derive(Debug, PartialEq)]
+impl Display for DownloadProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MIB: f64 = 1024.0 * 1024.0;
+
+        write!(
+            f,
+            "{:.1}% — {:.1}/{:.1} MiB — {:.1} MiB uploaded — {}/{} pieces",
+            self.percent(),
+            self.bytes_downloaded as f64 / MIB,
+            self.bytes_total as f64 / MIB,
+            self.bytes_uploaded as f64 / MIB,
+            self.pieces_fetched.iter().filter(|&&p| p).count(),
+            self.pieces_fetched.len()
+        )
+    }
+}
+
+/// The `event` key of an announce.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Display for AnnounceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use AnnounceEvent::*;
+
+        match self {
+            Started => write!(f, "started"),
+            Completed => write!(f, "completed"),
+            Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Peer {
     pub id: Option<PeerId>,
     pub ip: String,
@@ -174,26 +401,56 @@ impl Peer {
     pub fn hostname(&self) -> String {
         format!("{}:{}", self.ip, self.port)
     }
+
+    /// The peer's address, when `ip` is an IP literal rather than a DNS name.
+    /// IPv4-mapped IPv6 addresses come back as plain IPv4.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let ip = IpAddr::from_str(&self.ip).ok()?;
+        let port = u16::try_from(self.port).ok()?;
+
+        Some(SocketAddr::new(ip.to_canonical(), port))
+    }
+}
+
+/// `ip` with an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) written as the
+/// IPv4 address it maps, so a host isn't known twice. DNS names are kept.
+fn normalize_ip(ip: String) -> String {
+    match IpAddr::from_str(&ip) {
+        Ok(addr) => addr.to_canonical().to_string(),
+        Err(_) => ip,
+    }
 }
 
 impl Peer {
+    /// A peer in compact form: its IPv4 (4 bytes) or IPv6 (16 bytes)
+    /// address followed by the port, all big-endian.
     pub fn from_slice(b: &[u8]) -> Self {
-        let ip = b[0..=4]
-            .iter()
-            .map(|n| char::from(*n))
-            .intersperse('.')
-            .collect::<String>();
-
-        let mut buffer: [u8; 8] = [0; 8];
-
-        buffer.copy_from_slice(&b[5..]);
+        let (ip, port) = b.split_at(b.len() - 2);
+        let ip = <[u8; 4]>::try_from(ip)
+            .map(IpAddr::from)
+            .or_else(|_| <[u8; 16]>::try_from(ip).map(IpAddr::from))
+            .expect("compact peers are 6 or 18 bytes long");
 
         Peer {
             id: None,
-            ip,
-            port: usize::from_be_bytes(buffer),
+            ip: ip.to_canonical().to_string(),
+            port: u16::from_be_bytes([port[0], port[1]]) as usize,
         }
     }
+
+    /// The peers of a compact peer list, `ip_len` being 4 for `peers` and 16
+    /// for `peers6`.
+    fn compact_list(bytes: &[u8], ip_len: usize) -> Vec<Self> {
+        let chunks = bytes.chunks_exact(ip_len + 2);
+        if !chunks.remainder().is_empty() {
+            info!(
+                "Ignoring {} trailing bytes of a compact peer list",
+                chunks.remainder().len()
+            );
+        }
+
+        chunks.map(Peer::from_slice).collect()
+    }
 }
 
 impl Display for Peer {
@@ -206,6 +463,149 @@ impl Display for Peer {
     }
 }
 
+#[test]
+fn test_handshake_round_trip() {
+    let mut handshake = Handshake::new(
+        InfoHash::from_info_bytes(b"d4:name1:ae"),
+        PeerId::from_bytes(b"-LT0010-000000000000"),
+    );
+    assert_eq!(handshake.encode().len(), 68);
+    assert!(!handshake.supports_dht());
+
+    handshake.reserved[5] = 0x10;
+    handshake.reserved[7] = 0x05;
+
+    let decoded = Handshake::decode(&handshake.encode()).unwrap();
+    assert_eq!(decoded, handshake);
+    assert!(decoded.supports_dht());
+    assert!(decoded.supports_fast());
+    assert!(decoded.supports_extensions());
+
+    assert!(Handshake::decode(&handshake.encode()[..60]).is_err());
+}
+
+#[test]
+fn test_private_handshake_does_not_advertise_dht() {
+    let info_hash = InfoHash::from_info_bytes(b"d4:name1:ae");
+    let peer_id = PeerId::from_bytes(b"-LT0010-000000000000");
+
+    let private = Handshake::for_torrent(info_hash.clone(), peer_id.clone(), true);
+    assert!(!private.supports_dht());
+
+    let public = Handshake::for_torrent(info_hash, peer_id, false);
+    assert_eq!(public.supports_dht(), DHT_SUPPORTED);
+}
+
+#[test]
+fn test_handshake_layout() {
+    let info_hash = InfoHash::from_info_bytes(b"d4:name1:ae");
+    let peer_id = PeerId::from_bytes(b"-LT0010-000000000000");
+    let bytes = Handshake::new(info_hash.clone(), peer_id.clone()).encode();
+
+    assert_eq!(bytes[0], 19);
+    assert_eq!(&bytes[1..20], b"BitTorrent protocol");
+    assert_eq!(&bytes[20..28], &[0; 8]);
+    assert_eq!(&bytes[28..48], info_hash.as_bytes());
+    assert_eq!(&bytes[48..68], peer_id.as_bytes());
+    assert_eq!(bytes.len(), 68);
+}
+
+#[test]
+fn test_peer_id_from_seed() {
+    assert_eq!(PeerId::from_seed(7), PeerId::from_seed(7));
+    assert_ne!(PeerId::from_seed(7), PeerId::from_seed(8));
+    assert!(PeerId::from_seed(7).as_bytes().starts_with(b"-LT0010-"));
+    assert_eq!(PeerId::from_seed(7).as_bytes().len(), 20);
+}
+
+#[test]
+fn test_info_hash_and_peer_id_as_keys() {
+    let a = InfoHash::from_info_bytes(b"d4:name1:ae");
+    let b = InfoHash::from_info_bytes(b"d4:name1:be");
+    let hashes: std::collections::HashSet<InfoHash> = [a.clone(), b, a.clone()].into();
+    assert_eq!(hashes.len(), 2);
+    assert!(hashes.contains(&a));
+
+    let id = PeerId::from_bytes(b"-LT0010-000000000000");
+    let ids: std::collections::HashSet<PeerId> = [id.clone(), id.clone()].into();
+    assert_eq!(ids.len(), 1);
+}
+
+#[tokio::test]
+async fn test_handshake_times_out_on_silent_peer() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Accept and hold on to the connection without ever writing to it
+    let silent = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+    let mut conn = PeerConnection {
+        hostname: addr.ip().to_string(),
+        socket: TcpStream::connect(addr).await.unwrap(),
+        me_choked: true,
+        me_interested: false,
+        they_choked: true,
+        they_interested: false,
+    };
+
+    let result = conn
+        .handshake_within(
+            &InfoHash::from_info_bytes(b"d4:name1:ae"),
+            &PeerId::new(),
+            false,
+            std::time::Duration::from_millis(100),
+        )
+        .await;
+
+    assert!(matches!(
+        result,
+        Err(PeerConnectionError::SocketUnavailable(_))
+    ));
+    drop(silent);
+}
+
+#[tokio::test]
+async fn test_handshake_rejects_other_torrent() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Answers any handshake with one for another torrent
+    let other = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut theirs = [0u8; Handshake::LENGTH];
+        socket.read_exact(&mut theirs).await.unwrap();
+
+        let reply = Handshake::new(
+            InfoHash::from_info_bytes(b"d4:name5:othere"),
+            PeerId::from_bytes(b"-LT0010-111111111111"),
+        );
+        socket.write_all(&reply.encode()).await.unwrap();
+        socket
+    });
+
+    let mut conn = PeerConnection {
+        hostname: addr.ip().to_string(),
+        socket: TcpStream::connect(addr).await.unwrap(),
+        me_choked: true,
+        me_interested: false,
+        they_choked: true,
+        they_interested: false,
+    };
+
+    let result = conn
+        .handshake(
+            &InfoHash::from_info_bytes(b"d4:name1:ae"),
+            &PeerId::from_seed(0),
+            false,
+        )
+        .await;
+
+    let other_hash = hex::encode(InfoHash::from_info_bytes(b"d4:name5:othere").as_bytes());
+    assert!(matches!(
+        result,
+        Err(PeerConnectionError::InfoHashMismatch(e)) if e.contains(&other_hash)
+    ));
+    drop(other.await.unwrap());
+}
+
 #[test]
 fn test_peer_from_slice() {
     let slice: [u8; 6] = [10, 0, 0, 1, 0x1a, 0xe1];
@@ -220,6 +620,25 @@ fn test_peer_from_slice() {
     )
 }
 
+#[test]
+fn test_peer_ipv4_mapped_address() {
+    let mapped = Peer::from_bencode(b"d2:ip15:::ffff:10.0.0.14:porti6881ee").unwrap();
+    assert_eq!(mapped.ip, "10.0.0.1");
+    assert_eq!(mapped.socket_addr(), Some("10.0.0.1:6881".parse().unwrap()));
+
+    let unnormalized = Peer {
+        id: None,
+        ip: "::ffff:10.0.0.1".to_string(),
+        port: 6881,
+    };
+    assert_eq!(unnormalized.socket_addr(), mapped.socket_addr());
+
+    let v6 = Peer::from_bencode(b"d2:ip3:::14:porti6881ee").unwrap();
+    assert_eq!(v6.ip, "::1");
+    let name = Peer::from_bencode(b"d2:ip11:example.com4:porti6881ee").unwrap();
+    assert_eq!(name.ip, "example.com");
+}
+
 impl FromBencode for Peer {
     const EXPECTED_RECURSION_DEPTH: usize = 1;
 
@@ -235,24 +654,26 @@ impl FromBencode for Peer {
 
         while let Some(pair) = decoder.next_pair()? {
             match pair {
-                (b"id", val) => id = Some(PeerId::decode_bencode_object(val)?),
+                // BEP 3 calls it `peer id`, some trackers just `id`
+                (b"peer id" | b"id", val) => id = Some(PeerId::decode_bencode_object(val)?),
                 (b"ip", val) => ip = Some(String::decode_bencode_object(val)?),
                 (b"port", val) => port = Some(usize::decode_bencode_object(val)?),
-                (f, _) => {
-                    let field = String::from_utf8(f.to_vec()).expect("malformed key value");
-                    return Err(bendy::decoding::Error::unexpected_field(field));
-                }
+                // Trackers add their own keys, which don't matter here
+                (_, _) => {}
             }
         }
 
-        if let (None, None) = (ip.clone(), port) {
-            return Err(bendy::decoding::Error::missing_field("ip or port not set"));
-        }
+        let Some(ip) = ip else {
+            return Err(bendy::decoding::Error::missing_field("ip"));
+        };
+        let Some(port) = port else {
+            return Err(bendy::decoding::Error::missing_field("port"));
+        };
 
         Ok(Peer {
             id,
-            ip: ip.expect("should have set ip"),
-            port: port.expect("should have set port"),
+            ip: normalize_ip(ip),
+            port,
         })
     }
 }
@@ -266,6 +687,8 @@ pub struct PeerInfoResult {
     complete: u64,
     incomplete: u64,
     peers: Vec<Peer>,
+    /// Our address as the tracker saw it, a common extension to the spec
+    external_ip: Option<IpAddr>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -330,6 +753,10 @@ impl Display for PeerInfoResult {
             write!(f, "tracker id: {}\n", ti)?
         }
 
+        if let Some(ip) = &self.external_ip {
+            writeln!(f, "external ip: {}", ip)?
+        }
+
         write!(
             f,
             "    interval: {}\n    complete: {}\n    incomplete: {}\n    peers: {}",
@@ -350,6 +777,17 @@ impl FromBencode for PeerInfoResult {
     where
         Self: Sized,
     {
+        // Strict, so tests can hold trackers to the spec
+        PeerInfoResult::decode(object, true)
+    }
+}
+
+impl PeerInfoResult {
+    /// Decodes an announce response. `peers` and `interval` are required in
+    /// any case; a strict decode also requires `complete` and `incomplete`
+    /// and rejects unknown keys, where a lenient one warns and defaults the
+    /// counts to 0 and skips unknown keys.
+    fn decode(object: Object, strict: bool) -> Result<Self, bendy::decoding::Error> {
         let mut decoder = object.try_into_dictionary()?;
 
         let mut peers = None;
@@ -359,25 +797,30 @@ impl FromBencode for PeerInfoResult {
         let mut interval = None;
         let mut min_interval = None;
         let mut warning_message = None;
+        let mut external_ip = None;
 
         while let Some(pair) = decoder.next_pair()? {
             match pair {
-                (b"peers", val) => {
-                    let peer_bytes = val.try_into_bytes()?;
-
-                    let mut peer_list: Vec<Peer> = vec![];
-                    if let Ok(mut list) = Object::Bytes(peer_bytes).try_into_list() {
-                        while let Some(val) = list.next_object()? {
-                            peer_list.push(Peer::decode_bencode_object(val)?);
-                        }
-                    } else {
-                        for peer in peer_bytes.chunks(6) {
-                            peer_list.push(Peer::from_slice(peer));
+                // Trackers may send IPv4 peers in `peers`, either as a list of
+                // dictionaries or compact, and IPv6 ones in `peers6`, so
+                // peers from every key are kept
+                (b"peers", Object::List(mut list)) => {
+                    let peers = peers.get_or_insert_with(Vec::new);
+                    while let Some(val) = list.next_object()? {
+                        match Peer::decode_bencode_object(val) {
+                            Ok(peer) => peers.push(peer),
+                            Err(e) if strict => return Err(e),
+                            Err(e) => info!("Ignoring malformed peer: {}", e),
                         }
                     }
-                    peers = Some(peer_list);
                 }
-                (b"tracker_id", val) => tracker_id = Some(String::decode_bencode_object(val)?),
+                (b"peers", val) => peers
+                    .get_or_insert_with(Vec::new)
+                    .extend(Peer::compact_list(val.try_into_bytes()?, 4)),
+                (b"peers6", val) => peers
+                    .get_or_insert_with(Vec::new)
+                    .extend(Peer::compact_list(val.try_into_bytes()?, 16)),
+                (b"tracker id", val) => tracker_id = Some(String::decode_bencode_object(val)?),
                 (b"complete", val) => complete = Some(u64::decode_bencode_object(val)?),
                 (b"incomplete", val) => incomplete = Some(u64::decode_bencode_object(val)?),
                 (b"interval", val) => interval = Some(u64::decode_bencode_object(val)?),
@@ -385,50 +828,338 @@ impl FromBencode for PeerInfoResult {
                 (b"warning message", val) => {
                     warning_message = Some(String::decode_bencode_object(val)?)
                 }
+                (b"external ip", val) => {
+                    let ip = val.try_into_bytes()?;
+                    external_ip = match ip.len() {
+                        4 => <[u8; 4]>::try_from(ip).ok().map(IpAddr::from),
+                        16 => <[u8; 16]>::try_from(ip).ok().map(IpAddr::from),
+                        n => {
+                            info!("Ignoring {} byte long external ip", n);
+                            None
+                        }
+                    };
+                }
                 (f, _) => {
-                    let field = String::from_utf8(f.to_vec()).expect("malformed key value");
-                    return Err(bendy::decoding::Error::unexpected_field(field));
+                    let field = String::from_utf8_lossy(f).to_string();
+                    if strict {
+                        return Err(bendy::decoding::Error::unexpected_field(field));
+                    }
+
+                    info!("Ignoring unknown announce response key {:?}", field);
                 }
             }
         }
-        if let (None, None, None) = (interval, complete, incomplete) {
-            if let None = interval {
-                return Err(bendy::decoding::Error::missing_field("interval"));
-            }
 
-            if let None = complete {
-                return Err(bendy::decoding::Error::missing_field("complete"));
-            }
+        let Some(interval) = interval else {
+            return Err(bendy::decoding::Error::missing_field("interval"));
+        };
+
+        let Some(mut peers) = peers else {
+            return Err(bendy::decoding::Error::missing_field("peers"));
+        };
 
-            if let None = incomplete {
-                return Err(bendy::decoding::Error::missing_field("incomplete"));
+        // The same peer may be listed under several keys
+        let mut seen = HashSet::new();
+        peers.retain(|p| {
+            seen.insert(
+                p.socket_addr()
+                    .map_or_else(|| p.hostname(), |a| a.to_string()),
+            )
+        });
+
+        let count_or_default = |count: Option<u64>, field: &'static str| match count {
+            Some(c) => Ok(c),
+            None if strict => Err(bendy::decoding::Error::missing_field(field)),
+            None => {
+                info!("Announce response has no {:?}, assuming 0", field);
+                Ok(0)
             }
-        }
+        };
+
+        let complete = count_or_default(complete, "complete")?;
+        let incomplete = count_or_default(incomplete, "incomplete")?;
 
         Ok(PeerInfoResult {
             warning_message,
-            interval: interval.expect("should contain interval"),
+            interval,
             min_interval,
             tracker_id,
-            complete: complete.expect("should contain complete"),
-            incomplete: incomplete.expect("should contain incomplete"),
-            peers: peers.expect("should contain peers"),
+            complete,
+            incomplete,
+            peers,
+            external_ip,
         })
     }
-}
 
-impl PeerInfoResult {
+    pub fn tracker_id(&self) -> Option<&String> {
+        self.tracker_id.as_ref()
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    pub fn min_interval(&self) -> Option<u64> {
+        self.min_interval
+    }
+
+    pub fn peers(&self) -> &Vec<Peer> {
+        &self.peers
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    /// Keeps only the peers for which `f` returns true.
+    pub fn retain_peers<F: FnMut(&Peer) -> bool>(&mut self, f: F) {
+        self.peers.retain(f);
+    }
+
+    /// Leniently parses an announce response, as many trackers leave out
+    /// fields that aren't needed to find peers.
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, TorrentError> {
-        PeerInfoResult::from_bencode(bytes.as_slice())
+        let mut decoder = Decoder::new(bytes.as_slice());
+        let object = decoder
+            .next_object()
+            .map_err(|e| TorrentError::InvalidAnnounceResponse(e.to_string()))?
+            .ok_or_else(|| TorrentError::InvalidAnnounceResponse("empty response".into()))?;
+
+        PeerInfoResult::decode(object, false)
             .map_err(|e| TorrentError::InvalidAnnounceResponse(e.to_string()))
     }
 }
 
+#[test]
+fn test_peer_info_result_tracker_id() {
+    let response = b"d8:completei1e10:incompletei2e8:intervali60e5:peers0:10:tracker id6:abc123e";
+    let result = PeerInfoResult::from_bytes(response.to_vec()).unwrap();
+
+    assert_eq!(result.tracker_id(), Some(&"abc123".to_string()));
+}
+
+#[test]
+fn test_peer_info_result_merges_peer_keys() {
+    let mut response = b"d8:intervali60e5:peers12:".to_vec();
+    response.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe1]);
+    response.extend_from_slice(b"6:peers636:");
+    let v6: std::net::Ipv6Addr = "2001:db8::7".parse().unwrap();
+    response.extend_from_slice(&v6.octets());
+    response.extend_from_slice(&[0xc8, 0xd5]);
+    // 10.0.0.2 again, IPv4-mapped
+    let mapped = std::net::Ipv4Addr::new(10, 0, 0, 2).to_ipv6_mapped();
+    response.extend_from_slice(&mapped.octets());
+    response.extend_from_slice(&[0x1a, 0xe1]);
+    response.push(b'e');
+
+    let result = PeerInfoResult::from_bytes(response).unwrap();
+    let hostnames: Vec<String> = result.peers().iter().map(|p| p.hostname()).collect();
+
+    assert_eq!(
+        hostnames,
+        vec!["10.0.0.1:6881", "10.0.0.2:6881", "2001:db8::7:51413"]
+    );
+}
+
+#[test]
+fn test_peer_info_result_dictionary_peers() {
+    let response = b"d8:intervali60e5:peersld2:ip8:10.0.0.14:porti6881eeee";
+    let result = PeerInfoResult::from_bytes(response.to_vec()).unwrap();
+
+    assert_eq!(result.peers()[0].hostname(), "10.0.0.1:6881");
+}
+
+#[test]
+fn test_peer_info_result_bep3_dictionary_peers() {
+    let response = b"d8:intervali1800e5:peersld2:ip8:10.0.0.17:peer id20:-TR2940-0123456789ab4:porti6881e6:sourcei1eeee";
+    let result = PeerInfoResult::from_bytes(response.to_vec()).unwrap();
+
+    let peer = &result.peers()[0];
+    assert_eq!(peer.hostname(), "10.0.0.1:6881");
+    assert_eq!(
+        peer.id.as_ref().map(|id| id.as_bytes()),
+        Some(&b"-TR2940-0123456789ab"[..])
+    );
+}
+
+#[test]
+fn test_peer_info_result_malformed_dictionary_peers() {
+    for peer in [
+        &b"d4:porti6881ee"[..],
+        b"d2:ip8:10.0.0.2e",
+        b"d2:ip8:10.0.0.24:port4:6881e",
+        b"i3e",
+    ] {
+        let mut response = b"d8:intervali60e5:peersl".to_vec();
+        response.extend_from_slice(peer);
+        response.extend_from_slice(b"d2:ip8:10.0.0.14:porti6881eeee");
+
+        // Only the bad entry is dropped
+        let result = PeerInfoResult::from_bytes(response.clone()).unwrap();
+        let hostnames: Vec<String> = result.peers().iter().map(|p| p.hostname()).collect();
+        assert_eq!(hostnames, vec!["10.0.0.1:6881"]);
+
+        assert!(PeerInfoResult::from_bencode(&response).is_err());
+    }
+
+    // A key that isn't UTF-8 is just another unknown key
+    let odd_key = b"d2:ip8:10.0.0.14:porti6881e2:\xff\xfei1ee";
+    assert_eq!(
+        Peer::from_bencode(odd_key).unwrap().hostname(),
+        "10.0.0.1:6881"
+    );
+}
+
+#[test]
+fn test_peer_info_result_lenient_parse() {
+    let sloppy = b"d10:downloadedi3e8:intervali60e5:peers0:e";
+
+    let result = PeerInfoResult::from_bytes(sloppy.to_vec()).unwrap();
+    assert_eq!((result.complete, result.incomplete), (0, 0));
+    assert_eq!(result.interval, 60);
+
+    // The FromBencode impl is the strict decode
+    assert!(PeerInfoResult::from_bencode(sloppy).is_err());
+
+    // Without peers or interval there's nothing to work with
+    assert!(PeerInfoResult::from_bytes(b"d8:completei1e5:peers0:e".to_vec()).is_err());
+    assert!(PeerInfoResult::from_bytes(b"d8:completei1e8:intervali60ee".to_vec()).is_err());
+}
+
+#[test]
+fn test_peer_info_result_external_ip() {
+    let without = b"d8:completei1e10:incompletei2e8:intervali60e5:peers0:e";
+    let result = PeerInfoResult::from_bencode(without).unwrap();
+    assert_eq!(result.external_ip(), None);
+
+    let v4 =
+        b"d8:completei1e11:external ip4:\xcb\x00\x71\x0710:incompletei2e8:intervali60e5:peers0:e";
+    let result = PeerInfoResult::from_bencode(v4).unwrap();
+    assert_eq!(result.external_ip(), Some("203.0.113.7".parse().unwrap()));
+
+    let mut v6 = b"d11:external ip16:".to_vec();
+    v6.extend_from_slice(
+        &"2001:db8::7"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    v6.extend_from_slice(b"8:intervali60e5:peers0:e");
+    let result = PeerInfoResult::from_bytes(v6).unwrap();
+    assert_eq!(result.external_ip(), Some("2001:db8::7".parse().unwrap()));
+}
+
+/// A tracker's counts for one torrent, from a scrape.
+#[derive(Debug, PartialEq)]
+pub struct ScrapeResult {
+    pub complete: u64,
+    pub downloaded: u64,
+    pub incomplete: u64,
+}
+
+impl Display for ScrapeResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "seeders: {}, leechers: {}, completed downloads: {}",
+            self.complete, self.incomplete, self.downloaded
+        )
+    }
+}
+
+impl ScrapeResult {
+    /// Picks the counts of `info_hash` out of a scrape response, where they
+    /// sit under `files`, keyed by the raw info hash. Trackers answer with an
+    /// empty `files` for torrents they don't know.
+    pub fn from_bytes(bytes: Vec<u8>, info_hash: &InfoHash) -> Result<Self, TorrentError> {
+        let invalid =
+            |e: bendy::decoding::Error| TorrentError::InvalidScrapeResponse(e.to_string());
+
+        let mut decoder = Decoder::new(bytes.as_slice());
+        let object = decoder
+            .next_object()
+            .map_err(invalid)?
+            .ok_or_else(|| TorrentError::InvalidScrapeResponse("empty response".into()))?;
+
+        let mut dict = object.try_into_dictionary().map_err(invalid)?;
+        let mut counts = None;
+
+        while let Some(pair) = dict.next_pair().map_err(invalid)? {
+            if let (b"files", val) = pair {
+                let mut files = val.try_into_dictionary().map_err(invalid)?;
+
+                while let Some((hash, val)) = files.next_pair().map_err(invalid)? {
+                    if hash == info_hash.as_bytes() {
+                        counts = Some(ScrapeResult::decode_counts(val).map_err(invalid)?);
+                    }
+                }
+            }
+        }
+
+        counts.ok_or_else(|| TorrentError::TorrentNotTracked(hex::encode(info_hash.as_bytes())))
+    }
+
+    fn decode_counts(object: Object) -> Result<Self, bendy::decoding::Error> {
+        let mut dict = object.try_into_dictionary()?;
+
+        let mut complete = None;
+        let mut downloaded = None;
+        let mut incomplete = None;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"complete", val) => complete = Some(u64::decode_bencode_object(val)?),
+                (b"downloaded", val) => downloaded = Some(u64::decode_bencode_object(val)?),
+                (b"incomplete", val) => incomplete = Some(u64::decode_bencode_object(val)?),
+                (_, _) => {}
+            }
+        }
+
+        Ok(ScrapeResult {
+            complete: complete.ok_or_else(|| bendy::decoding::Error::missing_field("complete"))?,
+            // Not every tracker keeps count of finished downloads
+            downloaded: downloaded.unwrap_or(0),
+            incomplete: incomplete
+                .ok_or_else(|| bendy::decoding::Error::missing_field("incomplete"))?,
+        })
+    }
+}
+
+#[test]
+fn test_scrape_result_for_tracked_torrent() {
+    let info_hash = InfoHash::for_tests();
+    let mut response = b"d5:filesd20:".to_vec();
+    response.extend_from_slice(info_hash.as_bytes());
+    response.extend_from_slice(b"d8:completei5e10:incompletei3eeee");
+
+    assert_eq!(
+        ScrapeResult::from_bytes(response, &info_hash).unwrap(),
+        ScrapeResult {
+            complete: 5,
+            downloaded: 0,
+            incomplete: 3
+        }
+    );
+}
+
+#[test]
+fn test_scrape_result_for_untracked_torrent() {
+    let info_hash = InfoHash::for_tests();
+
+    assert!(matches!(
+        ScrapeResult::from_bytes(b"d5:filesdee".to_vec(), &info_hash),
+        Err(TorrentError::TorrentNotTracked(_))
+    ));
+}
+
 #[derive(Debug)]
 pub enum TorrentError {
     TrackerError(String),
     InvalidAnnounceResponse(String),
     InvalidTrackerUrl(String),
+    TorrentFileUnavailable(String),
+    InvalidScrapeResponse(String),
+    TorrentNotTracked(String),
 }
 
 impl Into<String> for TorrentError {
@@ -445,6 +1176,37 @@ impl Display for TorrentError {
             TrackerError(e) => write!(f, "TrackerError: {}", e),
             InvalidAnnounceResponse(e) => write!(f, "AnnounceError: {}", e),
             InvalidTrackerUrl(e) => write!(f, "InvalidTrackerUrl: {}", e),
+            TorrentFileUnavailable(e) => write!(f, "TorrentFileUnavailable: {}", e),
+            InvalidScrapeResponse(e) => write!(f, "ScrapeError: {}", e),
+            TorrentNotTracked(e) => write!(f, "TorrentNotTracked: {}", e),
         }
     }
 }
+
+#[test]
+fn test_download_progress_display() {
+    let empty = DownloadProgress::default();
+    assert_eq!(
+        empty.to_string(),
+        "100.0% — 0.0/0.0 MiB — 0.0 MiB uploaded — 0/0 pieces"
+    );
+
+    let done = DownloadProgress {
+        bytes_total: 3 * 1024 * 1024,
+        bytes_downloaded: 3 * 1024 * 1024,
+        bytes_uploaded: 512 * 1024,
+        pieces_fetched: vec![true; 12],
+    };
+    assert_eq!(
+        done.to_string(),
+        "100.0% — 3.0/3.0 MiB — 0.5 MiB uploaded — 12/12 pieces"
+    );
+
+    let partial = DownloadProgress {
+        bytes_total: 4 * 1024 * 1024,
+        bytes_downloaded: 1024 * 1024,
+        pieces_fetched: vec![true, false, false, false],
+        ..DownloadProgress::default()
+    };
+    assert!(partial.to_string().starts_with("25.0% — 1.0/4.0 MiB"));
+}
@@ -0,0 +1,122 @@
+use std::{ffi::OsString, fmt::Display, path::Path};
+
+use clap::{ArgMatches, Command, parser::ValueSource};
+
+/// Name of the config file looked for in the data dir.
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Unreadable(String),
+    Malformed(String),
+    /// A key that isn't the long name of an option
+    UnknownKey(String),
+    /// A value that can't be given on the command line, like a table
+    UnsupportedValue(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ConfigError::*;
+
+        match self {
+            Unreadable(e) => write!(f, "ConfigError::Unreadable: {}", e),
+            Malformed(e) => write!(f, "ConfigError::Malformed: {}", e),
+            UnknownKey(k) => write!(f, "ConfigError::UnknownKey: {}", k),
+            UnsupportedValue(k) => write!(f, "ConfigError::UnsupportedValue: {}", k),
+        }
+    }
+}
+
+/// Reads the config file at `path`, `None` when there is none.
+pub fn read(path: &Path) -> Result<Option<String>, ConfigError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ConfigError::Unreadable(format!(
+            "{}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// The command line `args` with the options of the TOML config in
+/// `contents` added, so they are parsed and validated like any flag.
+///
+/// Keys are the options' long names (`max-announces = 8`). An option given
+/// on the command line wins over the config, which wins over the default.
+pub fn merge_args(
+    command: &Command,
+    matches: &ArgMatches,
+    args: Vec<OsString>,
+    contents: &str,
+) -> Result<Vec<OsString>, ConfigError> {
+    let table: toml::Table =
+        toml::from_str(contents).map_err(|e| ConfigError::Malformed(e.to_string()))?;
+
+    let mut args = args.into_iter();
+    let mut merged: Vec<OsString> = args.next().into_iter().collect();
+
+    for (key, value) in table {
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_long() == Some(key.as_str()) && key != "config")
+            .ok_or_else(|| ConfigError::UnknownKey(key.clone()))?;
+
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        let value = match value {
+            toml::Value::Boolean(true) => None,
+            toml::Value::Boolean(false) => continue,
+            toml::Value::String(s) => Some(s),
+            toml::Value::Integer(i) => Some(i.to_string()),
+            toml::Value::Float(f) => Some(f.to_string()),
+            _ => return Err(ConfigError::UnsupportedValue(key)),
+        };
+
+        merged.push(match value {
+            Some(value) => format!("--{}={}", key, value).into(),
+            None => format!("--{}", key).into(),
+        });
+    }
+
+    merged.extend(args);
+    Ok(merged)
+}
+
+#[test]
+fn test_command_line_overrides_config() {
+    let command = || {
+        Command::new("bt")
+            .arg(clap::arg!(--"max-announces" <N>).default_value("4"))
+            .arg(clap::arg!(--"query-separator" <SEP>))
+            .arg(clap::arg!(--lsd))
+            .arg(clap::arg!(--config <PATH>))
+            .arg(clap::arg!(<torrent>))
+    };
+    let cli: Vec<OsString> = ["bt", "--query-separator", ";", "a.torrent"]
+        .iter()
+        .map(OsString::from)
+        .collect();
+    let matches = command().get_matches_from(&cli);
+
+    let config = "max-announces = 8\nquery-separator = \"&\"\nlsd = true\n";
+    let merged = merge_args(&command(), &matches, cli, config).unwrap();
+    let merged = command().get_matches_from(merged);
+
+    assert_eq!(merged.get_one::<String>("max-announces").unwrap(), "8");
+    assert_eq!(merged.get_one::<String>("query-separator").unwrap(), ";");
+    assert!(merged.get_flag("lsd"));
+
+    for bad in [
+        "peers = 1",
+        "config = \"x\"",
+        "torrent = \"x\"",
+        "lsd = [1]",
+    ] {
+        assert!(merge_args(&command(), &matches, vec![], bad).is_err());
+    }
+}
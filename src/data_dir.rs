@@ -0,0 +1,71 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{bittorrent::PeerId, util::info};
+
+/// Where state that outlives a run is kept, such as our peer id.
+#[derive(Debug)]
+pub struct DataDir {
+    path: PathBuf,
+}
+
+impl DataDir {
+    /// `$XDG_STATE_HOME/bt`, falling back to `$HOME/.local/state/bt`.
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(state_home) = std::env::var_os("XDG_STATE_HOME").filter(|p| !p.is_empty()) {
+            return Some(PathBuf::from(state_home).join("bt"));
+        }
+
+        std::env::var_os("HOME")
+            .filter(|p| !p.is_empty())
+            .map(|home| PathBuf::from(home).join(".local/state/bt"))
+    }
+
+    /// Opens the data dir at `path`, creating it when absent.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(path)?;
+
+        Ok(DataDir {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The peer id used on previous runs, or a new one that is saved for
+    /// the next runs.
+    pub fn peer_id(&self) -> std::io::Result<PeerId> {
+        let peer_id_path = self.path.join("peer_id");
+
+        match std::fs::read(&peer_id_path) {
+            Ok(bytes) if bytes.len() == 20 => return Ok(PeerId::from_bytes(&bytes)),
+            Ok(_) => info!(
+                "Ignoring malformed peer id in {}, generating a new one",
+                peer_id_path.display()
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let peer_id = PeerId::new();
+        std::fs::write(&peer_id_path, peer_id.as_bytes())?;
+
+        Ok(peer_id)
+    }
+}
+
+#[test]
+fn test_peer_id_is_persisted() {
+    let path = crate::util::temp_path("data-dir-test");
+
+    let first = DataDir::open(&path).unwrap().peer_id().unwrap();
+    let second = DataDir::open(&path).unwrap().peer_id().unwrap();
+
+    std::fs::remove_dir_all(&path).unwrap();
+
+    assert_eq!(first, second);
+}
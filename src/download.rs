@@ -1,54 +1,365 @@
 use bendy::decoding::FromBencode;
+use rand::seq::SliceRandom;
 use reqwest::{Client, StatusCode, Url};
-use std::{sync::Arc, time::Duration};
-use tokio::{fs::File, sync::RwLock, task::JoinSet};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    net::TcpListener,
+    sync::{
+        Notify, RwLock, Semaphore,
+        mpsc::{Receiver, Sender},
+    },
+    task::JoinSet,
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
 
-use crate::bittorrent::{
-    AnnounceFailResult, DownloadProgress, PeerConnection, PeerInfoResult, TorrentError,
+use crate::{
+    announce_cache::AnnounceCache,
+    bittorrent::{
+        AnnounceEvent, AnnounceFailResult, DownloadProgress, Peer, PeerConnection, PeerInfoResult,
+        ScrapeResult, TorrentError,
+    },
+    ip_filter::IpFilter,
+    lsd,
+    peer_dump::PeerDump,
+    status::{self, Status},
+    util::{info, percent_encode, percent_encode_upper},
 };
 
-async fn announce(
-    tracker: &String,
-    info_hash: &crate::bittorrent::InfoHash,
-    peer_id: &crate::bittorrent::PeerId,
-    port: usize,
-    progress_lock: &RwLock<DownloadProgress>,
-) -> Result<PeerInfoResult, TorrentError> {
+/// How long dropping a [`DownloadSession`] may block waiting for the
+/// `event=stopped` announces to go out.
+const STOP_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Largest announce or scrape response read. Real ones are a few KiB.
+const MAX_TRACKER_RESPONSE: usize = 1024 * 1024;
+
+/// Largest torrent file downloaded. Those of huge torrents with small pieces
+/// run into the MiBs.
+const MAX_TORRENT_FILE: usize = 64 * 1024 * 1024;
+
+/// The conventional BitTorrent ports, tried when the requested one is taken.
+const LISTEN_PORTS: std::ops::RangeInclusive<u16> = 6881..=6889;
+
+/// Longest a failing tracker is left alone before being tried again.
+const MAX_ANNOUNCE_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Where peers found on the LAN are counted in the status and listed in the
+/// peer dump, after every tracker tier.
+const LOCAL_PEERS_TIER: usize = usize::MAX;
+
+/// How long a tier waits to announce again while none of its trackers has
+/// answered, and what failing trackers are backed off from.
+const ANNOUNCE_RETRY: Duration = Duration::from_secs(60);
+
+/// When to announce again after a tracker answered: its `interval`, but
+/// never before its `min interval`.
+fn next_announce(found_peers: &PeerInfoResult) -> Duration {
+    Duration::from_secs(
+        found_peers
+            .interval()
+            .max(found_peers.min_interval().unwrap_or(0)),
+    )
+}
+
+/// How long to leave a tracker alone after it failed `failures` times in a
+/// row: `interval` doubled for each failure, up to [`MAX_ANNOUNCE_BACKOFF`].
+fn announce_backoff(interval: Duration, failures: u32) -> Duration {
+    interval
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(MAX_ANNOUNCE_BACKOFF)
+}
+
+/// Builds the announce query string, with values already percent-encoded.
+/// When `event` is `None` it is derived from the download progress
+/// (`started` before any byte, `completed` once finished).
+fn announce_query(
+    ctx: &AnnounceContext,
+    progress: &DownloadProgress,
+    event: Option<AnnounceEvent>,
+    tracker_id: Option<&str>,
+) -> Vec<(&'static str, String)> {
     let mut qs = vec![
-        ("info_hash", info_hash.to_string()),
-        ("peer_id", peer_id.to_string()),
-        ("port", port.to_string()),
+        ("info_hash", percent_encode(ctx.info_hash.as_bytes())),
+        ("peer_id", percent_encode(ctx.peer_id.as_bytes())),
+        ("port", ctx.port.to_string()),
     ];
 
-    println!("{:?}", qs);
+    // Peer lists aren't requested compact, so without this every entry
+    // carries a 20 byte id nothing but the peer dump reads
+    if !ctx.peer_ids {
+        qs.push(("no_peer_id", "1".to_string()));
+    }
 
-    {
-        let progress = progress_lock.read().await;
+    // Otherwise the tracker uses the address the request came from. With
+    // one of each family, both are sent so a dual-stack tracker records
+    // both (BEP 7)
+    match ctx.ips.as_slice() {
+        [] => {}
+        [ip] => qs.push(("ip", percent_encode(ip.to_string().as_bytes()))),
+        ips => {
+            for ip in ips {
+                let key = if ip.is_ipv4() { "ipv4" } else { "ipv6" };
+                qs.push((key, percent_encode(ip.to_string().as_bytes())));
+            }
+        }
+    }
+
+    let mut derived_event = None;
+    if progress.bytes_downloaded > 0 {
+        qs.push(("downloaded", progress.bytes_downloaded.to_string()));
+        if progress.finished() {
+            derived_event = Some(AnnounceEvent::Completed);
+        }
+    } else {
+        derived_event = Some(AnnounceEvent::Started);
+    }
+
+    if let Some(e) = event.or(derived_event) {
+        qs.push(("event", e.to_string()));
+    }
+
+    // Trackers that hand out a tracker id expect it back on every announce
+    if let Some(id) = tracker_id {
+        qs.push(("trackerid", percent_encode(id.as_bytes())));
+    }
+
+    qs
+}
+
+/// `tracker` with `qs`, whose values are already percent-encoded, added to
+/// the query it may already have (like a passkey). Parameters are joined
+/// with `separator`.
+///
+/// The query is built by hand because reqwest's `.query()` would encode the
+/// `%` of the binary values a second time.
+fn tracker_url(
+    tracker: &str,
+    qs: &[(&'static str, String)],
+    separator: char,
+) -> Result<Url, TorrentError> {
+    let mut url =
+        Url::parse(tracker).map_err(|e| TorrentError::InvalidTrackerUrl(e.to_string()))?;
+
+    let ours = qs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(&separator.to_string());
+
+    let query = match url.query() {
+        Some(existing) if !existing.is_empty() => format!("{}{}{}", existing, separator, ours),
+        _ => ours,
+    };
+    url.set_query(Some(&query));
+
+    Ok(url)
+}
+
+/// What a download sends in every announce, whichever tracker it goes to.
+#[derive(Clone)]
+pub struct AnnounceContext {
+    pub info_hash: crate::bittorrent::InfoHash,
+    pub peer_id: crate::bittorrent::PeerId,
+    pub port: usize,
+    /// Addresses to announce instead of the one the tracker sees us on, at
+    /// most one per family
+    pub ips: Vec<IpAddr>,
+    /// Joins query parameters, `&` unless a legacy tracker needs `;`
+    pub query_separator: char,
+    /// Whether peer lists should come with peer ids
+    pub peer_ids: bool,
+    pub progress: Arc<RwLock<DownloadProgress>>,
+}
+
+/// Somewhere the peers of a torrent can be asked for, so the announce loop
+/// doesn't care how each source is reached.
+pub trait AnnounceSource: Send + Sync {
+    /// Identifies the source in logs and in the announce cache
+    fn name(&self) -> &str;
+
+    /// Whether the source takes its info hash escapes in upper case hex, as
+    /// learned from earlier announces.
+    fn upper_hex(&self) -> bool {
+        false
+    }
+
+    /// The tracker id the source last handed out, to be sent back.
+    fn tracker_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Announces the download and returns what the source answered, which
+    /// holds the peers along with when to ask again.
+    fn get_peers<'a>(
+        &'a self,
+        ctx: &'a AnnounceContext,
+    ) -> Pin<Box<dyn Future<Output = Result<PeerInfoResult, TorrentError>> + Send + 'a>>;
+}
+
+/// An HTTP(S) tracker. Remembers the tracker id it hands out, to send it
+/// back on later announces, and the hex case its info hash is accepted in.
+pub struct HttpTracker {
+    client: Client,
+    url: String,
+    tracker_id: std::sync::Mutex<Option<String>>,
+    upper_hex: AtomicBool,
+}
+
+impl HttpTracker {
+    pub fn new(client: Client, url: String) -> Self {
+        HttpTracker {
+            client,
+            url,
+            tracker_id: std::sync::Mutex::new(None),
+            upper_hex: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Whether a tracker's failure reason reads like it didn't recognize the
+/// info hash, which some trackers answer when its escapes aren't in the
+/// hex case they expect.
+fn rejects_info_hash(reason: &str) -> bool {
+    let reason = reason.to_lowercase();
+    ["info_hash", "info hash", "infohash"]
+        .iter()
+        .any(|s| reason.contains(s))
+}
 
-        if progress.bytes_downloaded > 0 {
-            qs.push(("downloaded", progress.bytes_downloaded.to_string()));
-            if progress.finished() {
-                qs.push(("event", "finished".to_string()));
+impl AnnounceSource for HttpTracker {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn upper_hex(&self) -> bool {
+        self.upper_hex.load(Ordering::Relaxed)
+    }
+
+    fn tracker_id(&self) -> Option<String> {
+        self.tracker_id
+            .lock()
+            .expect("tracker id lock poisoned")
+            .clone()
+    }
+
+    fn get_peers<'a>(
+        &'a self,
+        ctx: &'a AnnounceContext,
+    ) -> Pin<Box<dyn Future<Output = Result<PeerInfoResult, TorrentError>> + Send + 'a>> {
+        Box::pin(async move {
+            let tracker_id = self
+                .tracker_id
+                .lock()
+                .expect("tracker id lock poisoned")
+                .clone();
+
+            let upper_hex = self.upper_hex.load(Ordering::Relaxed);
+            let announce_in = |upper_hex| {
+                announce(
+                    &self.client,
+                    &self.url,
+                    ctx,
+                    None,
+                    tracker_id.as_deref(),
+                    upper_hex,
+                )
+            };
+
+            let result = match announce_in(upper_hex).await {
+                Err(TorrentError::TrackerError(reason)) if rejects_info_hash(&reason) => {
+                    info!(
+                        "{} rejected the info hash ({}), retrying it in {} case hex",
+                        self.url,
+                        reason,
+                        if upper_hex { "lower" } else { "upper" }
+                    );
+                    let result = announce_in(!upper_hex).await?;
+                    self.upper_hex.store(!upper_hex, Ordering::Relaxed);
+                    result
+                }
+                result => result?,
+            };
+
+            if let Some(id) = result.tracker_id() {
+                *self.tracker_id.lock().expect("tracker id lock poisoned") = Some(id.clone());
             }
-        } else {
-            qs.push(("event", "started".to_string()));
+
+            Ok(result)
+        })
+    }
+}
+
+/// The body of `response`, refused once it grows past `limit` bytes rather
+/// than holding whatever a broken or hostile server sends.
+async fn read_body(mut response: reqwest::Response, limit: usize) -> Result<Vec<u8>, String> {
+    let too_large = || format!("response is larger than {} bytes", limit);
+
+    if response.content_length().is_some_and(|l| l > limit as u64) {
+        return Err(too_large());
+    }
+
+    let mut body = vec![];
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| format!("unfinished response: {}", e))?
+    {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large());
         }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+async fn announce(
+    client: &Client,
+    tracker: &String,
+    ctx: &AnnounceContext,
+    event: Option<AnnounceEvent>,
+    tracker_id: Option<&str>,
+    upper_hex: bool,
+) -> Result<PeerInfoResult, TorrentError> {
+    let mut qs = {
+        let progress = ctx.progress.read().await;
+
+        announce_query(ctx, &progress, event, tracker_id)
         // dropping progress as then it can be released for other tasks
+    };
+
+    if upper_hex {
+        for (key, value) in qs.iter_mut() {
+            if *key == "info_hash" {
+                *value = percent_encode_upper(ctx.info_hash.as_bytes());
+            }
+        }
     }
 
-    let client = Client::new();
-    let url = Url::parse(tracker).map_err(|e| TorrentError::InvalidTrackerUrl(e.to_string()))?;
+    info!("{:?}", qs);
+
+    let url = tracker_url(tracker, &qs, ctx.query_separator)?;
 
-    match client.get(url.clone()).query(&qs).send().await {
+    match client.get(url).send().await {
         Ok(response) => {
             if response.status() != StatusCode::OK {
                 return Err(TorrentError::TrackerError("Error response".into()));
             }
 
-            let bytes = response
-                .bytes()
+            let bytes = read_body(response, MAX_TRACKER_RESPONSE)
                 .await
-                .map_err(|_| TorrentError::TrackerError("Unfinished response".into()))?;
+                .map_err(TorrentError::TrackerError)?;
 
             if let Ok(result) = AnnounceFailResult::from_bencode(bytes.to_vec().as_slice()) {
                 return Err(TorrentError::TrackerError(result.to_string()));
@@ -57,103 +368,748 @@ async fn announce(
             PeerInfoResult::from_bytes(bytes.to_vec())
         }
         Err(e) => {
-            println!("Error when announcing: {:?}", e);
+            eprintln!("Error when announcing: {:?}", e);
             Err(TorrentError::TrackerError("Error in request".into()))
         }
     }
 }
 
+/// The scrape URL of an announce URL, by the convention of replacing the
+/// last path segment's `announce` with `scrape`. Trackers whose URL doesn't
+/// follow it don't support scraping.
+fn scrape_url(tracker: &str) -> Result<Url, TorrentError> {
+    let mut url =
+        Url::parse(tracker).map_err(|e| TorrentError::InvalidTrackerUrl(e.to_string()))?;
+
+    let path = url.path().to_string();
+    let (dir, last) = path.rsplit_once('/').unwrap_or(("", path.as_str()));
+
+    if !last.starts_with("announce") {
+        return Err(TorrentError::InvalidTrackerUrl(format!(
+            "{} does not support scraping",
+            tracker
+        )));
+    }
+
+    url.set_path(&format!(
+        "{}/{}",
+        dir,
+        last.replacen("announce", "scrape", 1)
+    ));
+
+    Ok(url)
+}
+
+/// Asks `tracker` how many peers it knows for the torrent.
+pub async fn scrape(
+    client: &Client,
+    tracker: &str,
+    info_hash: &crate::bittorrent::InfoHash,
+) -> Result<ScrapeResult, TorrentError> {
+    let url = tracker_url(
+        scrape_url(tracker)?.as_str(),
+        &[("info_hash", percent_encode(info_hash.as_bytes()))],
+        '&',
+    )?;
+
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| TorrentError::TrackerError(e.to_string()))?;
+
+    if response.status() != StatusCode::OK {
+        return Err(TorrentError::TrackerError("Error response".into()));
+    }
+
+    let bytes = read_body(response, MAX_TRACKER_RESPONSE)
+        .await
+        .map_err(TorrentError::TrackerError)?;
+
+    if let Ok(result) = AnnounceFailResult::from_bencode(bytes.to_vec().as_slice()) {
+        return Err(TorrentError::TrackerError(result.to_string()));
+    }
+
+    ScrapeResult::from_bytes(bytes.to_vec(), info_hash)
+}
+
+/// A running download as seen by its trackers.
+///
+/// Dropping it fires a best-effort `event=stopped` announce to every tracker
+/// that accepted an announce, so they learn we left whether the download ends
+/// through Ctrl-C, an early return or a panic unwinding out of it.
+///
+/// `Drop` can't be async, and by the time it runs the download's runtime may
+/// already be shutting down, in which case anything spawned on it would be
+/// cancelled before the request goes out. The stop announces therefore run on
+/// a short-lived runtime in their own thread, and the dropping thread blocks
+/// for at most [`STOP_ANNOUNCE_TIMEOUT`]. Nothing is sent when the process
+/// exits without unwinding (`std::process::exit`, an abort, SIGKILL).
+pub struct DownloadSession {
+    /// Trackers that accepted an announce, and so should be told we stopped.
+    announced: Arc<std::sync::Mutex<Vec<AnnouncedTracker>>>,
+    ctx: AnnounceContext,
+    tls: TlsOptions,
+}
+
+/// A tracker that accepted an announce, with what its stop announce needs
+/// to be accepted too.
+struct AnnouncedTracker {
+    url: String,
+    upper_hex: bool,
+    tracker_id: Option<String>,
+}
+
+impl DownloadSession {
+    pub fn new(ctx: AnnounceContext, tls: TlsOptions) -> Self {
+        DownloadSession {
+            announced: Arc::new(std::sync::Mutex::new(vec![])),
+            ctx,
+            tls,
+        }
+    }
+}
+
+impl Drop for DownloadSession {
+    fn drop(&mut self) {
+        let trackers = match self.announced.lock() {
+            Ok(mut announced) => std::mem::take(&mut *announced),
+            // A panic while holding the lock doesn't make the list wrong
+            Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+        };
+
+        if trackers.is_empty() {
+            return;
+        }
+
+        let ctx = self.ctx.clone();
+        let tls = self.tls.clone();
+
+        let stopper = std::thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(e) => {
+                    eprintln!("Could not announce stop to trackers: {}", e);
+                    return;
+                }
+            };
+
+            runtime.block_on(async {
+                // The download's client may hold connections owned by the
+                // other runtime, so this one gets its own.
+                let client = match http_client(&tls) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        eprintln!("Could not announce stop to trackers: {}", e);
+                        return;
+                    }
+                };
+                let mut set = JoinSet::new();
+
+                for t in trackers {
+                    let ctx = ctx.clone();
+                    let client = client.clone();
+
+                    set.spawn(async move {
+                        if let Err(e) = announce(
+                            &client,
+                            &t.url,
+                            &ctx,
+                            Some(AnnounceEvent::Stopped),
+                            t.tracker_id.as_deref(),
+                            t.upper_hex,
+                        )
+                        .await
+                        {
+                            eprintln!("Error when announcing stop to {}: {}", t.url, e);
+                        }
+                    });
+                }
+
+                if tokio::time::timeout(STOP_ANNOUNCE_TIMEOUT, set.join_all())
+                    .await
+                    .is_err()
+                {
+                    eprintln!("Timed out announcing stop to trackers");
+                }
+            });
+        });
+
+        let _ = stopper.join();
+    }
+}
+
+/// How HTTPS trackers and torrent URLs are verified.
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    /// Trusted besides the system roots
+    pub ca_cert: Option<reqwest::Certificate>,
+    /// Skips certificate verification altogether
+    pub insecure: bool,
+}
+
+/// Reads the PEM certificate at `path`, failing when it doesn't parse.
+pub fn load_ca_cert(path: &std::path::Path) -> Result<reqwest::Certificate, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    reqwest::Certificate::from_pem(&pem).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// The HTTP client for trackers and torrent URLs.
+pub fn http_client(tls: &TlsOptions) -> Result<Client, String> {
+    let mut builder = Client::builder().danger_accept_invalid_certs(tls.insecure);
+
+    if let Some(cert) = &tls.ca_cert {
+        builder = builder.add_root_certificate(cert.clone());
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Downloads a `.torrent` file served over HTTP(S).
+pub async fn fetch_torrent_file(client: &Client, url: Url) -> Result<Vec<u8>, TorrentError> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| TorrentError::TorrentFileUnavailable(e.to_string()))?;
+
+    if response.status() != StatusCode::OK {
+        return Err(TorrentError::TorrentFileUnavailable(format!(
+            "server answered {}",
+            response.status()
+        )));
+    }
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string();
+
+    let bytes = read_body(response, MAX_TORRENT_FILE)
+        .await
+        .map_err(TorrentError::TorrentFileUnavailable)?;
+
+    // A metainfo file is a bencoded dictionary, anything else is most likely
+    // an error or landing page
+    if bytes.first() != Some(&b'd') {
+        return Err(TorrentError::TorrentFileUnavailable(format!(
+            "response is not a bencoded torrent file (content type: {})",
+            content_type
+        )));
+    }
+
+    Ok(bytes.to_vec())
+}
+
+/// Listens for peers on `requested`, or on the first free port of
+/// [`LISTEN_PORTS`] when it is taken or not given.
+pub async fn bind_listener(requested: Option<u16>) -> std::io::Result<TcpListener> {
+    let candidates = requested
+        .into_iter()
+        .chain(LISTEN_PORTS.filter(|p| Some(*p) != requested));
+
+    let mut last_error = None;
+    for port in candidates {
+        match TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => {
+                if let Some(requested) = requested
+                    && requested != port
+                {
+                    info!("Port {} is taken, using {}", requested, port);
+                }
+                return Ok(listener);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.expect("there is always a port to try"))
+}
+
+/// Cancels the download once `limit` has passed.
+pub fn cancel_after(cancel: &CancellationToken, limit: Duration) {
+    let cancel = cancel.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(limit) => {
+                info!("Stopping, the download ran for {}s", limit.as_secs());
+                cancel.cancel();
+            }
+            _ = cancel.cancelled() => {}
+        }
+    });
+}
+
+/// Tells when a download stopped making progress: it had started, peers
+/// are known, and yet no new bytes came for `timeout` while there is still
+/// something left to fetch.
+struct StallDetector {
+    timeout: Duration,
+    last_downloaded: u64,
+    since: Instant,
+    stalled: bool,
+}
+
+impl StallDetector {
+    fn new(timeout: Duration, now: Instant) -> Self {
+        StallDetector {
+            timeout,
+            last_downloaded: 0,
+            since: now,
+            stalled: false,
+        }
+    }
+
+    /// Whether it is time to look for more peers, given what the download
+    /// had and whether any peers were known at `now`. The wait starts over
+    /// each time, so while stalled this is true once per `timeout` rather
+    /// than on every check.
+    fn observe(&mut self, progress: &DownloadProgress, has_peers: bool, now: Instant) -> bool {
+        // Before the first byte or without peers, the trackers' interval is
+        // as good as any
+        if progress.bytes_downloaded != self.last_downloaded
+            || progress.bytes_downloaded == 0
+            || progress.finished()
+            || !has_peers
+        {
+            self.last_downloaded = progress.bytes_downloaded;
+            self.since = now;
+            self.stalled = false;
+            return false;
+        }
+
+        if now.duration_since(self.since) < self.timeout {
+            return false;
+        }
+
+        self.since = now;
+        self.stalled = true;
+        true
+    }
+
+    fn stalled(&self) -> bool {
+        self.stalled
+    }
+}
+
+/// How a download talks to trackers and peers.
+pub struct DownloadOptions {
+    pub port: usize,
+    /// At most one address per family
+    pub announce_ip: Vec<IpAddr>,
+    pub query_separator: char,
+    /// Whether to ask trackers for the ids of the peers they return
+    pub peer_ids: bool,
+    pub ip_filter: Arc<IpFilter>,
+    /// Maximum number of announce requests in flight at once, however many
+    /// tiers the torrent has.
+    pub max_announces: usize,
+    /// Where announce results are remembered across runs, if anywhere.
+    pub announce_cache: Option<Arc<AnnounceCache>>,
+    /// Whether to look for peers on the local network
+    pub lsd: bool,
+    /// Stops the download when cancelled
+    pub cancel: CancellationToken,
+    /// Where to keep the list of known peers, if anywhere
+    pub peer_dump: Option<Arc<PeerDump>>,
+    /// Where to answer status requests, if anywhere
+    pub status_listener: Option<TcpListener>,
+    /// Torrent name, as the status endpoint reports it
+    pub name: String,
+    /// Tiers to start announcing to while downloading, like the new ones
+    /// of a reloaded torrent file
+    pub added_tiers: Option<Receiver<Vec<Vec<String>>>>,
+    /// How long without new bytes before the trackers are asked for more
+    /// peers ahead of their interval, if ever
+    pub stall_timeout: Option<Duration>,
+    /// Certificate verification for the stop announces, which need their
+    /// own client
+    pub tls: TlsOptions,
+}
+
+/// How far a download got by the time it ended.
+#[derive(Debug)]
+pub struct DownloadResult {
+    pub progress: DownloadProgress,
+    /// Whether it ended because it was cancelled
+    pub cancelled: bool,
+}
+
+impl DownloadResult {
+    pub fn completed(&self) -> bool {
+        self.progress.finished()
+    }
+}
+
 pub async fn download_files(
-    maybe_trackers: Option<Vec<String>>,
+    client: Client,
+    maybe_tiers: Option<Vec<Vec<String>>>,
     maybe_web_seeds: Option<Vec<String>>,
     info_hash: crate::bittorrent::InfoHash,
     peer_id: crate::bittorrent::PeerId,
-    port: usize,
-) -> () {
+    total_length: u64,
+    options: DownloadOptions,
+) -> DownloadResult {
     let mut set = JoinSet::new();
 
     let download_progress: Arc<RwLock<DownloadProgress>> =
-        Arc::new(RwLock::new(DownloadProgress::default()));
+        Arc::new(RwLock::new(DownloadProgress {
+            bytes_total: total_length,
+            ..DownloadProgress::default()
+        }));
 
     let (tx, mut rx) = tokio::sync::mpsc::channel(64);
 
-    if let Some(trackers) = maybe_trackers {
-        println!(
-            "Trying to download from these trackers: \n{}",
-            trackers
-                .iter()
-                .map(|t| format!("    {}\n", t))
-                .collect::<String>()
-        );
+    let ctx = AnnounceContext {
+        info_hash,
+        peer_id,
+        port: options.port,
+        ips: options.announce_ip,
+        query_separator: options.query_separator,
+        peer_ids: options.peer_ids,
+        progress: download_progress.clone(),
+    };
+
+    let session = DownloadSession::new(ctx.clone(), options.tls.clone());
 
-        for t in trackers {
-            let thread_info_hash = info_hash.clone();
-            let thread_peer_id = peer_id.clone();
-            let thread_download_progress = download_progress.clone();
+    let announce_slots = Arc::new(Semaphore::new(options.max_announces.max(1)));
 
-            let thread_tx = tx.clone();
+    let status = Arc::new(Status::new(
+        options.name.clone(),
+        ctx.info_hash.clone(),
+        download_progress.clone(),
+    ));
+    let status_server = options
+        .status_listener
+        .map(|listener| tokio::spawn(status::serve(listener, status.clone())));
 
-            set.spawn(async move {
+    // Wakes the tiers up to announce before their interval is up
+    let reannounce = Arc::new(Notify::new());
+
+    // Announces to one tier, for the torrent's tiers as well as those added
+    // while downloading
+    let spawn_tier = |set: &mut JoinSet<()>,
+                      tx: &Sender<String>,
+                      tier_index: usize,
+                      tier: Vec<String>| {
+        let mut tier: Vec<Box<dyn AnnounceSource>> = tier
+            .into_iter()
+            .map(|url| Box::new(HttpTracker::new(client.clone(), url)) as Box<dyn AnnounceSource>)
+            .collect();
+        let thread_ctx = ctx.clone();
+        let thread_ip_filter = options.ip_filter.clone();
+        let thread_announce_slots = announce_slots.clone();
+        let thread_announced = session.announced.clone();
+        let thread_announce_cache = options.announce_cache.clone();
+        let thread_peer_dump = options.peer_dump.clone();
+        let thread_status = status.clone();
+        let thread_reannounce = reannounce.clone();
+
+        let thread_tx = tx.clone();
+
+        // BEP 12: trackers within a tier are tried in a random order
+        tier.shuffle(&mut rand::thread_rng());
+
+        set.spawn(async move {
                 let _ = thread_tx
                     .send(format!("starting thread to announce the torrent"))
                     .await;
 
                 let mut peers: Vec<PeerConnection> = Vec::new();
-                let announce_interval = Duration::from_secs(60);
+                // Consecutive failures of each tracker and when it may be
+                // tried again
+                let mut backoffs: HashMap<String, (u32, Instant)> = HashMap::new();
+                let mut first_iteration = true;
+                // What the tier last answered, to keep going on while all
+                // of its trackers are down
+                let mut known_peers: Vec<Peer> = vec![];
+                // The tracker's min interval, which even a stall doesn't
+                // get to announce before
+                let mut earliest_reannounce = Instant::now();
 
                 loop {
-                    match announce(
-                        &t,
-                        &thread_info_hash,
-                        &thread_peer_id,
-                        port,
-                        &thread_download_progress,
-                    )
-                    .await
-                    {
-                        Ok(found_peers) => {
+                    // Right after a restart the tier may have been announced
+                    // to moments ago, so reuse what it answered then rather
+                    // than announcing again before its min interval is up
+                    if std::mem::take(&mut first_iteration) {
+                        let cached = thread_announce_cache.as_ref().and_then(|cache| {
+                            tier.iter()
+                                .find_map(|t| cache.fresh(t.name()).map(|fresh| (t.name(), fresh)))
+                        });
+
+                        if let Some((t, (cached_peers, remaining))) = cached {
+                            known_peers = cached_peers;
+                            known_peers.retain(|p| {
+                                thread_ip_filter.permits_peer(p)
+                            });
+
+                            thread_status.set_tier_peers(tier_index, known_peers.len());
+                            if let Some(dump) = &thread_peer_dump {
+                                dump.update(tier_index, known_peers.clone());
+                            }
+
                             let _ = thread_tx
-                                .send(format!("Got these peers {}", found_peers))
+                                .send(format!(
+                                    "Reusing {} peers {} returned on a previous run, announcing again in {}s",
+                                    known_peers.len(),
+                                    t,
+                                    remaining.as_secs()
+                                ))
                                 .await;
-                            // peers.sort_by_key(|p| p.hostname.clone());
-                            // for p in found_peers.peers {
-                            //     let hostname = p.hostname();
-                            //     if let Err(_) =
-                            //         peers.binary_search_by_key(&hostname, |p| p.hostname.clone())
-                            //     {
-                            //         // Peer not found in current peer list, so make a connection to him
-                            //         match PeerConnection::connect(
-                            //             &hostname,
-                            //             &thread_info_hash,
-                            //             &thread_peer_id,
-                            //         )
-                            //         .await
-                            //         {
-                            //             Ok(c) => peers.push(c),
-                            //             Err(e) => {
-                            //                 println!("Could not connect to peer at {}: {}", hostname, e)
-                            //             }
-                            //         }
-                            //     }
-                            // }
+
+                            tokio::time::sleep(remaining).await;
+                            continue;
                         }
-                        Err(e) => {
-                            let _ = thread_tx
-                                .send(format!("Error when announcing: {}", e))
-                                .await;
+                    }
+
+                    // Only one tracker per tier is announced to: the first
+                    // one that answers, which then moves to the front so it
+                    // is tried first next time
+                    let mut answered = false;
+                    // As the tracker that answers asks, sooner while none does
+                    let mut next_wait = ANNOUNCE_RETRY;
+                    for i in 0..tier.len() {
+                        let t = tier[i].name().to_string();
+
+                        if backoffs
+                            .get(&t)
+                            .is_some_and(|(_, retry_at)| Instant::now() < *retry_at)
+                        {
+                            continue;
+                        }
+
+                        let result = {
+                            let _slot = thread_announce_slots
+                                .acquire()
+                                .await
+                                .expect("announce slots are never closed");
+
+                            tier[i].get_peers(&thread_ctx).await
+                        };
+
+                        match result {
+                            Ok(mut found_peers) => {
+                                backoffs.remove(&t);
+                                next_wait = next_announce(&found_peers);
+                                earliest_reannounce = Instant::now()
+                                    + Duration::from_secs(found_peers.min_interval().unwrap_or(0));
+
+                                if let Some(ip) = found_peers.external_ip() {
+                                    let _ = thread_tx
+                                        .send(format!("{} sees us as {}", t, ip))
+                                        .await;
+                                }
+
+                                if let Some(cache) = &thread_announce_cache {
+                                    cache.store(
+                                        &t,
+                                        found_peers
+                                            .min_interval()
+                                            .unwrap_or(found_peers.interval()),
+                                        found_peers.peers().clone(),
+                                    );
+                                }
+
+                                {
+                                    let mut announced = thread_announced
+                                        .lock()
+                                        .expect("announced trackers lock poisoned");
+                                    let tracker = AnnouncedTracker {
+                                        url: t.clone(),
+                                        upper_hex: tier[i].upper_hex(),
+                                        tracker_id: tier[i].tracker_id(),
+                                    };
+                                    match announced.iter_mut().find(|a| a.url == t) {
+                                        Some(entry) => *entry = tracker,
+                                        None => announced.push(tracker),
+                                    }
+                                }
+
+                                let source = tier.remove(i);
+                                tier.insert(0, source);
+
+                                found_peers.retain_peers(|p| {
+                                    thread_ip_filter.permits_peer(p)
+                                });
+
+                                answered = true;
+                                known_peers = found_peers.peers().clone();
+
+                                thread_status.set_tier_peers(tier_index, known_peers.len());
+                                if let Some(dump) = &thread_peer_dump {
+                                    dump.update(tier_index, known_peers.clone());
+                                }
+
+                                let _ = thread_tx
+                                    .send(format!("Got these peers {}", found_peers))
+                                    .await;
+                                // peers.sort_by_key(|p| p.hostname.clone());
+                                // for p in found_peers.peers {
+                                //     let hostname = p.hostname();
+                                //     if let Err(_) =
+                                //         peers.binary_search_by_key(&hostname, |p| p.hostname.clone())
+                                //     {
+                                //         // Peer not found in current peer list, so make a connection to him
+                                //         match PeerConnection::connect(
+                                //             &hostname,
+                                //             &thread_info_hash,
+                                //             &thread_peer_id,
+                                //         )
+                                //         .await
+                                //         {
+                                //             Ok(c) => peers.push(c),
+                                //             Err(e) => {
+                                //                 println!("Could not connect to peer at {}: {}", hostname, e)
+                                //             }
+                                //         }
+                                //     }
+                                // }
+
+                                break;
+                            }
+                            Err(e) => {
+                                let failures = backoffs.get(&t).map_or(0, |(n, _)| *n) + 1;
+                                let wait = announce_backoff(ANNOUNCE_RETRY, failures);
+                                backoffs.insert(t.clone(), (failures, Instant::now() + wait));
+
+                                // An error, so not through the channel's info!
+                                eprintln!(
+                                    "Error when announcing to {}: {}, backing off for {}s",
+                                    t,
+                                    e,
+                                    wait.as_secs()
+                                );
+                            }
                         }
                     }
 
-                    tokio::time::sleep(announce_interval).await;
+                    if !answered {
+                        if known_peers.is_empty()
+                            && let Some(cached_peers) =
+                                thread_announce_cache.as_ref().and_then(|cache| {
+                                    tier.iter().find_map(|t| cache.last_known(t.name()))
+                                })
+                        {
+                            known_peers = cached_peers;
+                            known_peers.retain(|p| {
+                                thread_ip_filter.permits_peer(p)
+                            });
+                        }
+                        thread_status.set_tier_peers(tier_index, known_peers.len());
+
+                        let _ = thread_tx
+                            .send(format!(
+                                "Every tracker of the tier failed, running on {} previously known peers until one answers",
+                                known_peers.len()
+                            ))
+                            .await;
+                    }
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(next_wait) => {}
+                        _ = thread_reannounce.notified() => {
+                            tokio::time::sleep_until(earliest_reannounce).await;
+                        }
+                    }
                 }
             });
+    };
+
+    let mut tier_count = 0;
+    if let Some(tiers) = maybe_tiers {
+        info!(
+            "Trying to download from these trackers: \n{}",
+            tiers
+                .iter()
+                .enumerate()
+                .map(|(i, tier)| format!("    tier {}: {}\n", i, tier.join(", ")))
+                .collect::<String>()
+        );
+
+        for tier in tiers {
+            spawn_tier(&mut set, &tx, tier_count, tier);
+            tier_count += 1;
         }
     } else {
-        println!("this torrent doesnt have any defined tracker");
+        info!("this torrent doesnt have any defined tracker");
+    }
+
+    if let Some(timeout) = options.stall_timeout
+        && tier_count > 0
+    {
+        let progress = download_progress.clone();
+        let status = status.clone();
+        let reannounce = reannounce.clone();
+        let tx = tx.clone();
+
+        set.spawn(async move {
+            let mut detector = StallDetector::new(timeout, Instant::now());
+            let mut checks = tokio::time::interval(Duration::from_secs(1));
+
+            loop {
+                checks.tick().await;
+
+                let has_peers = status.peers() > 0;
+                if detector.observe(&*progress.read().await, has_peers, Instant::now()) {
+                    let _ = tx
+                        .send(format!(
+                            "No progress for {}s, asking the trackers for more peers",
+                            timeout.as_secs()
+                        ))
+                        .await;
+                    reannounce.notify_waiters();
+                }
+                status.set_stalled(detector.stalled());
+            }
+        });
+    }
+
+    if options.lsd {
+        let (peer_tx, mut peer_rx) = tokio::sync::mpsc::channel(16);
+        for group in [lsd::LSD_V4, lsd::LSD_V6] {
+            set.spawn(lsd::discover(
+                group,
+                ctx.info_hash.clone(),
+                u16::try_from(options.port).unwrap_or(6881),
+                options.ip_filter.clone(),
+                peer_tx.clone(),
+                tx.clone(),
+            ));
+        }
+
+        // Local peers are listed next to the tiers' own
+        let status = status.clone();
+        let peer_dump = options.peer_dump.clone();
+        let tx = tx.clone();
+        set.spawn(async move {
+            let mut local_peers: Vec<Peer> = vec![];
+
+            while let Some(peer) = peer_rx.recv().await {
+                if local_peers.contains(&peer) {
+                    continue;
+                }
+
+                let _ = tx
+                    .send(format!("Found local peer {}", peer.hostname()))
+                    .await;
+                local_peers.push(peer);
+
+                status.set_tier_peers(LOCAL_PEERS_TIER, local_peers.len());
+                if let Some(dump) = &peer_dump {
+                    dump.update(LOCAL_PEERS_TIER, local_peers.clone());
+                }
+            }
+        });
     }
 
     if let Some(web_seeds) = maybe_web_seeds {
-        println!(
+        info!(
             "This torrent may download from these web seeds:\n{}",
             web_seeds
                 .iter()
@@ -161,25 +1117,671 @@ pub async fn download_files(
                 .collect::<String>()
         );
     } else {
-        println!("this torrent doesnt have webseeds");
+        info!("this torrent doesnt have webseeds");
     }
 
-    while let Some(msg) = rx.recv().await {
-        println!("{}", msg);
+    // Only the tasks hold on to the channel, so it closes once they all end
+    let weak_tx = tx.downgrade();
+    drop(tx);
+    let mut added_tiers = options.added_tiers;
+
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => info!("{}", msg),
+                None => {
+                    set.join_all().await;
+                    break;
+                }
+            },
+            Some(tiers) = async {
+                match added_tiers.as_mut() {
+                    Some(added) => added.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                if let Some(tx) = weak_tx.upgrade() {
+                    for tier in tiers {
+                        spawn_tier(&mut set, &tx, tier_count, tier);
+                        tier_count += 1;
+                    }
+                }
+            }
+            _ = options.cancel.cancelled() => {
+                info!("Stopping download");
+                set.abort_all();
+                break;
+            }
+        }
     }
 
-    set.join_all().await;
+    if let Some(server) = status_server {
+        server.abort();
+    }
 
-    ()
+    // The stop announces report the progress, so they go out before it is
+    // taken. Dropping blocks, which a runtime worker mustn't
+    let _ = tokio::task::spawn_blocking(move || drop(session)).await;
+
+    DownloadResult {
+        progress: std::mem::take(&mut *download_progress.write().await),
+        cancelled: options.cancel.is_cancelled(),
+    }
 }
 
 pub async fn download_single_file(
     pieces: Vec<String>,
-    maybe_trackers: Option<Vec<String>>,
+    maybe_tiers: Option<Vec<Vec<String>>>,
     maybe_web_seeds: Option<Vec<String>>,
     file_handle: &mut File,
-) -> () {
+    length: u64,
+    cancel: CancellationToken,
+) -> DownloadResult {
+    // An empty file is complete once it exists, there is nothing to fetch.
+    // One that already had data is refused before getting here
+    if length == 0 {
+        info!("The torrent's file is empty, nothing to download");
+
+        return DownloadResult {
+            progress: DownloadProgress::default(),
+            cancelled: false,
+        };
+    }
+
     let mut pieces_downloaded: Vec<bool> = Vec::with_capacity(pieces.len());
 
-    ()
+    // Nothing is fetched for single file torrents yet
+    DownloadResult {
+        progress: DownloadProgress {
+            bytes_total: length,
+            ..DownloadProgress::default()
+        },
+        cancelled: cancel.is_cancelled(),
+    }
+}
+
+#[cfg(test)]
+fn test_context(ip: Option<IpAddr>, peer_ids: bool) -> AnnounceContext {
+    AnnounceContext {
+        info_hash: crate::bittorrent::InfoHash::for_tests(),
+        peer_id: crate::bittorrent::PeerId::from_seed(0),
+        port: 6881,
+        ips: ip.into_iter().collect(),
+        query_separator: '&',
+        peer_ids,
+        progress: Arc::new(RwLock::new(DownloadProgress::default())),
+    }
+}
+
+#[test]
+fn test_announce_query_sends_tracker_id_back() {
+    let ctx = test_context(None, false);
+    let progress = DownloadProgress::default();
+
+    let first = announce_query(&ctx, &progress, None, None);
+    assert!(!first.iter().any(|(k, _)| *k == "trackerid"));
+
+    let next = announce_query(&ctx, &progress, None, Some("abc123"));
+    assert!(next.contains(&("trackerid", "abc123".to_string())));
+}
+
+#[test]
+fn test_announce_query_sends_ip_override() {
+    let progress = DownloadProgress::default();
+
+    let inferred = announce_query(&test_context(None, false), &progress, None, None);
+    assert!(!inferred.iter().any(|(k, _)| *k == "ip"));
+
+    let v4 = test_context(Some("203.0.113.7".parse().unwrap()), false);
+    let v4 = announce_query(&v4, &progress, None, None);
+    assert!(v4.contains(&("ip", "203.0.113.7".to_string())));
+
+    let v6 = test_context(Some("2001:db8::7".parse().unwrap()), false);
+    let v6 = announce_query(&v6, &progress, None, None);
+    assert!(v6.contains(&("ip", "2001%3adb8%3a%3a7".to_string())));
+
+    let dual = AnnounceContext {
+        ips: vec![
+            "203.0.113.7".parse().unwrap(),
+            "2001:db8::7".parse().unwrap(),
+        ],
+        ..test_context(None, false)
+    };
+    let dual = announce_query(&dual, &progress, None, None);
+    assert!(!dual.iter().any(|(k, _)| *k == "ip"));
+    assert!(dual.contains(&("ipv4", "203.0.113.7".to_string())));
+    assert!(dual.contains(&("ipv6", "2001%3adb8%3a%3a7".to_string())));
+}
+
+#[test]
+fn test_announce_query_no_peer_id() {
+    let progress = DownloadProgress::default();
+
+    let without = announce_query(&test_context(None, false), &progress, None, None);
+    assert!(without.contains(&("no_peer_id", "1".to_string())));
+
+    let with = announce_query(&test_context(None, true), &progress, None, None);
+    assert!(!with.iter().any(|(k, _)| *k == "no_peer_id"));
+}
+
+#[test]
+fn test_announce_backoff_doubles_up_to_cap() {
+    let interval = Duration::from_secs(60);
+
+    assert_eq!(announce_backoff(interval, 1), Duration::from_secs(120));
+    assert_eq!(announce_backoff(interval, 2), Duration::from_secs(240));
+    assert_eq!(announce_backoff(interval, 4), Duration::from_secs(960));
+    assert_eq!(announce_backoff(interval, 5), MAX_ANNOUNCE_BACKOFF);
+    assert_eq!(announce_backoff(interval, 100), MAX_ANNOUNCE_BACKOFF);
+}
+
+#[test]
+fn test_next_announce_respects_min_interval() {
+    let found = |response: &[u8]| PeerInfoResult::from_bytes(response.to_vec()).unwrap();
+
+    assert_eq!(
+        next_announce(&found(b"d8:intervali1800e5:peers0:e")),
+        Duration::from_secs(1800)
+    );
+    assert_eq!(
+        next_announce(&found(b"d8:intervali1800e12:min intervali900e5:peers0:e")),
+        Duration::from_secs(1800)
+    );
+    assert_eq!(
+        next_announce(&found(b"d8:intervali30e12:min intervali120e5:peers0:e")),
+        Duration::from_secs(120)
+    );
+}
+
+#[test]
+fn test_tracker_url_query_separator() {
+    let qs = [
+        ("info_hash", "%12%34".to_string()),
+        ("port", "6881".to_string()),
+    ];
+
+    assert_eq!(
+        tracker_url("http://t.example/announce", &qs, '&')
+            .unwrap()
+            .as_str(),
+        "http://t.example/announce?info_hash=%12%34&port=6881"
+    );
+    assert_eq!(
+        tracker_url("http://t.example/announce", &qs, ';')
+            .unwrap()
+            .as_str(),
+        "http://t.example/announce?info_hash=%12%34;port=6881"
+    );
+}
+
+#[test]
+fn test_tracker_url_keeps_existing_query() {
+    let qs = [
+        ("info_hash", "%12%34".to_string()),
+        ("port", "6881".to_string()),
+    ];
+
+    assert_eq!(
+        tracker_url(
+            "https://t.example/announce?passkey=abc&source=XYZ",
+            &qs,
+            '&'
+        )
+        .unwrap()
+        .as_str(),
+        "https://t.example/announce?passkey=abc&source=XYZ&info_hash=%12%34&port=6881"
+    );
+    assert_eq!(
+        tracker_url("http://t.example/announce?", &qs, '&')
+            .unwrap()
+            .as_str(),
+        "http://t.example/announce?info_hash=%12%34&port=6881"
+    );
+}
+
+#[test]
+fn test_scrape_url() {
+    assert_eq!(
+        scrape_url("http://example.com/x/announce.php?passkey=a")
+            .unwrap()
+            .as_str(),
+        "http://example.com/x/scrape.php?passkey=a"
+    );
+    assert!(scrape_url("http://example.com/a").is_err());
+}
+
+/// Answers one HTTP request with `head` and `body`, like a tracker would.
+/// The handle resolves to the request it received.
+#[cfg(test)]
+async fn serve_once(
+    head: String,
+    body: Vec<u8>,
+) -> (std::net::SocketAddr, tokio::task::JoinHandle<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = [0u8; 4096];
+        let len = socket.read(&mut request).await.unwrap_or(0);
+
+        // The client may hang up before reading it all
+        let _ = socket.write_all(head.as_bytes()).await;
+        let _ = socket.write_all(&body).await;
+
+        String::from_utf8_lossy(&request[..len]).into_owned()
+    });
+
+    (addr, handle)
+}
+
+#[tokio::test]
+async fn test_announce_decodes_gzipped_response() {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder
+        .write_all(b"d8:intervali1800e5:peers0:10:tracker id7:gzippede")
+        .unwrap();
+    let body = encoder.finish().unwrap();
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let (addr, _) = serve_once(head, body).await;
+
+    let result = announce(
+        &Client::new(),
+        &format!("http://{}/announce", addr),
+        &test_context(None, false),
+        None,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(result.interval(), 1800);
+    assert_eq!(result.tracker_id(), Some(&"gzipped".to_string()));
+}
+
+#[tokio::test]
+async fn test_oversized_responses_are_refused() {
+    let body = vec![b'x'; MAX_TRACKER_RESPONSE + 1];
+
+    // Announced up front
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let (addr, _) = serve_once(head, body.clone()).await;
+    let result = announce(
+        &Client::new(),
+        &format!("http://{}/announce", addr),
+        &test_context(None, false),
+        None,
+        None,
+        false,
+    )
+    .await;
+    assert!(matches!(result, Err(TorrentError::TrackerError(e)) if e.contains("larger than")));
+
+    // Only found out while reading
+    let head = "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_string();
+    let (addr, _) = serve_once(head, body).await;
+    let result = announce(
+        &Client::new(),
+        &format!("http://{}/announce", addr),
+        &test_context(None, false),
+        None,
+        None,
+        false,
+    )
+    .await;
+    assert!(matches!(result, Err(TorrentError::TrackerError(e)) if e.contains("larger than")));
+}
+
+#[tokio::test]
+async fn test_zero_length_single_file_completes() {
+    let torrent =
+        b"d8:announce3:t/a4:infod6:lengthi0e4:name5:empty12:piece lengthi16384e6:pieces0:ee";
+    let meta = crate::metainfo::MetaInfoFile::from_bencode(torrent).unwrap();
+    let tiers = meta.tracker_tiers(&[]);
+    let crate::metainfo::Info::SingleFileInfo { pieces, length, .. } = meta.info else {
+        panic!("expected a single file torrent");
+    };
+    assert!(pieces.is_empty());
+
+    let path = crate::util::temp_path("empty-test");
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .await
+        .unwrap();
+
+    let result = download_single_file(
+        pieces,
+        tiers,
+        None,
+        &mut file,
+        length,
+        CancellationToken::new(),
+    )
+    .await;
+    let created = std::fs::metadata(&path).unwrap().len();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(result.completed());
+    assert_eq!(created, 0);
+}
+
+#[tokio::test]
+async fn test_bind_listener_falls_back_when_taken() {
+    // Occupy the requested port, so the fallback range is used
+    let taken = TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .unwrap();
+    let taken_port = taken.local_addr().unwrap().port();
+
+    match bind_listener(Some(taken_port)).await {
+        Ok(listener) => {
+            assert!(LISTEN_PORTS.contains(&listener.local_addr().unwrap().port()));
+        }
+        // Every conventional port is in use on this machine
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse),
+    }
+}
+
+#[tokio::test]
+async fn test_download_stops_at_deadline() {
+    let cancel = CancellationToken::new();
+    cancel_after(&cancel, Duration::from_millis(200));
+
+    let started = Instant::now();
+    let result = download_files(
+        Client::new(),
+        // Nothing listens there, so the tier would retry forever
+        Some(vec![vec!["http://127.0.0.1:1/announce".to_string()]]),
+        None,
+        crate::bittorrent::InfoHash::for_tests(),
+        crate::bittorrent::PeerId::from_seed(0),
+        100,
+        DownloadOptions {
+            port: 6881,
+            announce_ip: vec![],
+            query_separator: '&',
+            peer_ids: false,
+            ip_filter: Arc::new(IpFilter::default()),
+            max_announces: 1,
+            announce_cache: None,
+            lsd: false,
+            cancel,
+            peer_dump: None,
+            status_listener: None,
+            name: "test".to_string(),
+            added_tiers: None,
+            tls: TlsOptions::default(),
+            stall_timeout: None,
+        },
+    )
+    .await;
+
+    assert!(result.cancelled);
+    assert!(!result.completed());
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn test_fresh_cached_peers_are_used() {
+    let dir = crate::util::temp_path("fresh-cache-test");
+    let data_dir = crate::data_dir::DataDir::open(&dir).unwrap();
+    let info_hash = crate::bittorrent::InfoHash::for_tests();
+    let tracker = "http://127.0.0.1:1/announce";
+    let cache = Arc::new(AnnounceCache::load(&data_dir, &info_hash));
+    cache.store(
+        tracker,
+        600,
+        vec![Peer {
+            id: None,
+            ip: "10.0.0.1".to_string(),
+            port: 6881,
+        }],
+    );
+    let dump_path = dir.join("peers");
+
+    let cancel = CancellationToken::new();
+    cancel_after(&cancel, Duration::from_millis(200));
+    download_files(
+        Client::new(),
+        Some(vec![vec![tracker.to_string()]]),
+        None,
+        info_hash,
+        crate::bittorrent::PeerId::from_seed(0),
+        100,
+        DownloadOptions {
+            port: 6881,
+            announce_ip: vec![],
+            query_separator: '&',
+            peer_ids: false,
+            ip_filter: Arc::new(IpFilter::default()),
+            max_announces: 1,
+            announce_cache: Some(cache),
+            lsd: false,
+            cancel,
+            peer_dump: Some(Arc::new(PeerDump::new(dump_path.clone()))),
+            status_listener: None,
+            name: "test".to_string(),
+            added_tiers: None,
+            tls: TlsOptions::default(),
+            stall_timeout: None,
+        },
+    )
+    .await;
+
+    let dumped = std::fs::read_to_string(&dump_path);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(dumped.unwrap(), "10.0.0.1:6881\n");
+}
+
+#[tokio::test]
+async fn test_info_hash_hex_case_fallback() {
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A tracker that only recognizes the info hash in one hex case
+    async fn tracker(upper: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            let ctx = test_context(None, false);
+            let expected = if upper {
+                percent_encode_upper(ctx.info_hash.as_bytes())
+            } else {
+                percent_encode(ctx.info_hash.as_bytes())
+            };
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 4096];
+                let len = socket.read(&mut request).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let request = String::from_utf8_lossy(&request[..len]);
+                let body: &[u8] = if request.contains(&format!("info_hash={}&", expected)) {
+                    b"d8:intervali1800e5:peers0:e"
+                } else {
+                    b"d14:failure reason17:invalid info_hashe"
+                };
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(body).await;
+            }
+        });
+        (url, requests)
+    }
+
+    let ctx = test_context(None, false);
+    assert_ne!(
+        percent_encode(ctx.info_hash.as_bytes()),
+        percent_encode_upper(ctx.info_hash.as_bytes())
+    );
+
+    // Lower case is accepted right away
+    let (url, requests) = tracker(false).await;
+    let lower = HttpTracker::new(Client::new(), url);
+    assert_eq!(lower.get_peers(&ctx).await.unwrap().interval(), 1800);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert!(!lower.upper_hex());
+
+    // Upper case takes a retry, then is used from the start
+    let (url, requests) = tracker(true).await;
+    let upper = HttpTracker::new(Client::new(), url);
+    assert_eq!(upper.get_peers(&ctx).await.unwrap().interval(), 1800);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    upper.get_peers(&ctx).await.unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    // and kept for the stop announce
+    assert!(upper.upper_hex());
+}
+
+#[test]
+fn test_ca_cert_must_parse() {
+    let dir = crate::util::temp_path("ca-cert");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    assert!(load_ca_cert(&dir.join("missing.pem")).is_err());
+
+    let garbage = dir.join("garbage.pem");
+    std::fs::write(
+        &garbage,
+        "-----BEGIN CERTIFICATE-----\nnot a cert\n-----END CERTIFICATE-----\n",
+    )
+    .unwrap();
+    assert!(load_ca_cert(&garbage).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let insecure = TlsOptions {
+        insecure: true,
+        ..TlsOptions::default()
+    };
+    assert!(http_client(&insecure).is_ok());
+}
+
+#[test]
+fn test_stall_detector() {
+    let start = Instant::now();
+    let mut detector = StallDetector::new(Duration::from_secs(30), start);
+    let mut progress = DownloadProgress {
+        bytes_total: 100,
+        ..DownloadProgress::default()
+    };
+    let at = |secs| start + Duration::from_secs(secs);
+
+    // Nothing fetched yet is not a stall
+    assert!(!detector.observe(&progress, true, at(100)));
+
+    progress.bytes_downloaded = 10;
+    assert!(!detector.observe(&progress, true, at(101)));
+    assert!(!detector.observe(&progress, true, at(130)));
+    assert!(detector.observe(&progress, true, at(131)));
+    assert!(detector.stalled());
+
+    // Once per timeout while it stays stalled
+    assert!(!detector.observe(&progress, true, at(145)));
+    assert!(detector.observe(&progress, true, at(161)));
+
+    // Nor is having no peers to get bytes from
+    assert!(!detector.observe(&progress, false, at(200)));
+    assert!(!detector.stalled());
+    assert!(!detector.observe(&progress, true, at(229)));
+    assert!(detector.observe(&progress, true, at(230)));
+
+    progress.bytes_downloaded = 20;
+    assert!(!detector.observe(&progress, true, at(231)));
+    assert!(!detector.stalled());
+
+    progress.bytes_downloaded = 100;
+    assert!(!detector.observe(&progress, true, at(300)));
+    assert!(!detector.observe(&progress, true, at(400)));
+    assert!(!detector.stalled());
+}
+
+#[tokio::test]
+async fn test_stop_announce_is_sent_as_tracker_expects() {
+    let body = b"d8:intervali1800e5:peers0:e".to_vec();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let (addr, tracker) = serve_once(head, body).await;
+
+    let ctx = test_context(None, false);
+    let info_hash = percent_encode_upper(ctx.info_hash.as_bytes());
+    let session = DownloadSession::new(ctx, TlsOptions::default());
+    session.announced.lock().unwrap().push(AnnouncedTracker {
+        url: format!("http://{}/announce", addr),
+        upper_hex: true,
+        tracker_id: Some("abc123".to_string()),
+    });
+    tokio::task::spawn_blocking(move || drop(session))
+        .await
+        .unwrap();
+
+    let request = tracker.await.unwrap();
+    let request_line = request.lines().next().unwrap();
+    assert!(request_line.contains("event=stopped"), "{}", request_line);
+    assert!(
+        request_line.contains("trackerid=abc123"),
+        "{}",
+        request_line
+    );
+    assert!(
+        request_line.contains(&format!("info_hash={}&", info_hash)),
+        "{}",
+        request_line
+    );
+}
+
+#[tokio::test]
+async fn test_announce_request_keeps_passkey() {
+    let body = b"d8:intervali1800e5:peers0:e".to_vec();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let (addr, tracker) = serve_once(head, body).await;
+
+    let ctx = test_context(None, false);
+    announce(
+        &Client::new(),
+        &format!("http://{}/announce?passkey=abc123", addr),
+        &ctx,
+        None,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+
+    let request = tracker.await.unwrap();
+    let request_line = request.lines().next().unwrap();
+    assert!(
+        request_line.starts_with("GET /announce?passkey=abc123&info_hash="),
+        "{}",
+        request_line
+    );
+    assert!(request_line.contains(&format!(
+        "info_hash={}&",
+        percent_encode(ctx.info_hash.as_bytes())
+    )));
 }
@@ -0,0 +1,574 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::metainfo::Info;
+
+#[derive(Debug, PartialEq)]
+pub enum FileMapperError {
+    /// Two files of the torrent would be written to the same path
+    DuplicatePath(String),
+    /// A file's path is also the directory of another of its files
+    NestedPath(String),
+    /// The torrent's name isn't a plain file or directory name, so it could
+    /// be written outside the download dir
+    UnsafeName(String),
+}
+
+impl Display for FileMapperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use FileMapperError::*;
+
+        match self {
+            DuplicatePath(p) => write!(f, "FileMapperError::DuplicatePath: {}", p),
+            NestedPath(p) => write!(f, "FileMapperError::NestedPath: {}", p),
+            UnsafeName(n) => write!(f, "FileMapperError::UnsafeName: {}", n),
+        }
+    }
+}
+
+/// Where one file of a torrent sits in the torrent's concatenated data.
+#[derive(Debug, PartialEq)]
+pub struct FileSpan {
+    pub path: Vec<String>,
+    pub offset: u64,
+    pub length: u64,
+    /// Padding (BEP 47) takes up its bytes but is never written
+    pub padding: bool,
+    pub executable: bool,
+    /// Target of a symlink, relative to the torrent's root
+    pub symlink: Option<Vec<String>>,
+}
+
+impl FileSpan {
+    /// Indices of the pieces holding this file's bytes, `None` for a
+    /// zero-length file, which occupies no piece at all.
+    pub fn pieces(&self, piece_length: u64) -> Option<RangeInclusive<u64>> {
+        if self.length == 0 || piece_length == 0 {
+            return None;
+        }
+
+        let first = self.offset / piece_length;
+        let last = (self.offset + self.length - 1) / piece_length;
+
+        Some(first..=last)
+    }
+}
+
+/// Maps the files of a torrent onto byte ranges and pieces.
+#[derive(Debug, PartialEq)]
+pub struct FileMapper {
+    piece_length: u64,
+    files: Vec<FileSpan>,
+}
+
+impl FileMapper {
+    /// Fails when two files share a path, as one would overwrite the other,
+    /// when a file's path is the directory of another, and when the
+    /// torrent's name is anything but a single plain name.
+    pub fn new(info: &Info) -> Result<Self, FileMapperError> {
+        let name = match info {
+            Info::SingleFileInfo { name, .. } | Info::MultiFileInfo { name, .. } => name,
+        };
+        let mut components = Path::new(name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(FileMapperError::UnsafeName(name.clone()));
+        }
+
+        Ok(match info {
+            Info::SingleFileInfo {
+                name,
+                piece_length,
+                length,
+                ..
+            } => FileMapper {
+                piece_length: *piece_length,
+                files: vec![FileSpan {
+                    path: vec![name.clone()],
+                    offset: 0,
+                    length: *length,
+                    padding: false,
+                    executable: false,
+                    symlink: None,
+                }],
+            },
+            Info::MultiFileInfo {
+                piece_length,
+                files,
+                ..
+            } => {
+                let mut offset = 0;
+                let mut spans = vec![];
+                let mut seen = HashSet::new();
+
+                for f in files {
+                    // Padding files of the same size share a path. Compared
+                    // as paths, since `dir/a` is written where `dir`, `a` is
+                    if !f.is_padding() && !seen.insert(f.path().iter().collect::<PathBuf>()) {
+                        return Err(FileMapperError::DuplicatePath(f.path().join("/")));
+                    }
+
+                    spans.push(FileSpan {
+                        path: f.path().clone(),
+                        offset,
+                        length: f.length(),
+                        padding: f.is_padding(),
+                        executable: f.is_executable(),
+                        symlink: f.symlink_path().cloned(),
+                    });
+                    offset += f.length();
+                }
+
+                if let Some(nested) = seen
+                    .iter()
+                    .find(|p| p.ancestors().skip(1).any(|a| seen.contains(a)))
+                {
+                    return Err(FileMapperError::NestedPath(nested.display().to_string()));
+                }
+
+                FileMapper {
+                    piece_length: *piece_length,
+                    files: spans,
+                }
+            }
+        })
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn files(&self) -> &[FileSpan] {
+        &self.files
+    }
+
+    /// Creates every file of the torrent under `dir` that doesn't exist yet,
+    /// along with its parent directories. Zero-length files hold no piece, so
+    /// this is the only time they are written. Padding files are skipped,
+    /// executable ones get their mode and symlinks are linked.
+    pub fn create_files(&self, dir: &Path) -> std::io::Result<()> {
+        let files: Vec<(&FileSpan, PathBuf)> = self
+            .files
+            .iter()
+            .filter(|f| !f.padding)
+            .map(|f| (f, f.path.iter().collect()))
+            .collect();
+
+        // Keep a hostile path like `../x` from escaping the download dir,
+        // checking them all before anything is created. Symlink targets are
+        // relative to the torrent's root, so they are held to the same rule
+        if let Some(unsafe_path) = files
+            .iter()
+            .flat_map(|(f, p)| {
+                [
+                    Some(p.clone()),
+                    f.symlink.as_ref().map(|t| t.iter().collect()),
+                ]
+            })
+            .flatten()
+            .find(|p| !is_contained(p))
+        {
+            return Err(unsafe_path_error(&unsafe_path));
+        }
+
+        for (file, relative) in files {
+            // Links already on disk, from an earlier run or not, are as bad
+            if let Some(link) = relative
+                .ancestors()
+                .skip(1)
+                .filter(|a| !a.as_os_str().is_empty())
+                .find(|a| is_symlink(&dir.join(a)))
+            {
+                return Err(unsafe_path_error(link));
+            }
+
+            let path = dir.join(&relative);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            if let Some(target) = &file.symlink {
+                // From the link's own directory back up to the torrent's root
+                let mut link: PathBuf = relative.iter().skip(1).map(|_| "..").collect();
+                link.extend(target);
+
+                if std::fs::symlink_metadata(&path).is_err() {
+                    symlink(&link, &path)?;
+                }
+                continue;
+            }
+
+            if is_symlink(&path) {
+                return Err(unsafe_path_error(&relative));
+            }
+
+            let created = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+
+            if file.executable {
+                set_executable(&created)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bytes in the whole torrent, padding included.
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|f| f.length).sum()
+    }
+}
+
+/// Whether `path` is made of plain names only, so it stays under whatever
+/// directory it is joined to. An empty path would be the directory itself.
+fn is_contained(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+fn is_symlink(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+fn unsafe_path_error(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("unsafe file path {}", path.display()),
+    )
+}
+
+#[cfg(unix)]
+fn symlink(link: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(link, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_link: &Path, path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("can't create symlink {} on this platform", path.display()),
+    ))
+}
+
+#[cfg(unix)]
+fn set_executable(file: &std::fs::File) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    file.set_permissions(permissions)
+}
+
+// Only unix has an executable bit to set
+#[cfg(not(unix))]
+fn set_executable(_file: &std::fs::File) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Fails unless files can be created in `dir`. Read-only permissions are
+/// honoured even for users who could write anyway, like root.
+pub fn check_writable(dir: &Path) -> std::io::Result<()> {
+    let metadata = std::fs::metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotADirectory,
+            "not a directory",
+        ));
+    }
+
+    if metadata.permissions().readonly() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "it is read-only",
+        ));
+    }
+
+    let probe = dir.join(format!(".bt-write-check-{}", std::process::id()));
+    std::fs::File::create(&probe)?;
+    std::fs::remove_file(&probe)
+}
+
+impl Display for FileMapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, file) in self.files().iter().enumerate() {
+            let pieces = match file.pieces(self.piece_length) {
+                Some(p) => format!("[{}..={}]", p.start(), p.end()),
+                None => "none".to_string(),
+            };
+
+            writeln!(
+                f,
+                "{}: {}{}, bytes [{}..{}), pieces {}",
+                i,
+                file.path.join("/"),
+                if file.padding { " (padding)" } else { "" },
+                file.offset,
+                file.offset + file.length,
+                pieces
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_file_piece_ranges() {
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld6:lengthi10e4:pathl1:aeed6:lengthi0e4:pathl1:beed6:lengthi7e4:pathl1:ceee4:name1:t12:piece lengthi4e6:pieces80:01234567890123456789012345678901234567890123456789012345678901234567890123456789e",
+    )
+    .unwrap();
+    let mapper = FileMapper::new(&info).unwrap();
+
+    let pieces: Vec<_> = mapper.files().iter().map(|f| f.pieces(4)).collect();
+
+    assert_eq!(pieces, vec![Some(0..=2), None, Some(2..=4)]);
+    // The zero-length file takes no room between its neighbours
+    assert_eq!(mapper.files()[1].offset, 10);
+    assert_eq!(mapper.files()[2].offset, 10);
+    assert_eq!(mapper.total_length(), 17);
+}
+
+#[test]
+fn test_duplicate_paths_are_rejected() {
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld6:lengthi1e4:pathl3:dir1:aeed6:lengthi2e4:pathl1:beed6:lengthi3e4:pathl3:dir1:aeee4:name1:t12:piece lengthi4e6:pieces20:01234567890123456789e",
+    )
+    .unwrap();
+
+    assert_eq!(
+        FileMapper::new(&info),
+        Err(FileMapperError::DuplicatePath("dir/a".to_string()))
+    );
+}
+
+#[test]
+fn test_paths_are_compared_once_joined() {
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld6:lengthi1e4:pathl3:dir1:aeed6:lengthi2e4:pathl5:dir/aeee4:name1:t12:piece lengthi4e6:pieces20:01234567890123456789e",
+    )
+    .unwrap();
+
+    assert_eq!(
+        FileMapper::new(&info),
+        Err(FileMapperError::DuplicatePath("dir/a".to_string()))
+    );
+
+    // `a` can't be both a file and the directory holding `a/b`
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld6:lengthi1e4:pathl1:aeed6:lengthi2e4:pathl1:a1:beee4:name1:t12:piece lengthi4e6:pieces20:01234567890123456789e",
+    )
+    .unwrap();
+
+    assert_eq!(
+        FileMapper::new(&info),
+        Err(FileMapperError::NestedPath("a/b".to_string()))
+    );
+}
+
+#[test]
+fn test_create_files_includes_zero_length_ones() {
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld6:lengthi10e4:pathl1:aeed6:lengthi0e4:pathl5:empty1:beed6:lengthi7e4:pathl1:ceee4:name1:t12:piece lengthi4e6:pieces80:01234567890123456789012345678901234567890123456789012345678901234567890123456789e",
+    )
+    .unwrap();
+    let dir = crate::util::temp_path("create-files-test");
+
+    FileMapper::new(&info).unwrap().create_files(&dir).unwrap();
+
+    let empty = std::fs::metadata(dir.join("empty/b"));
+    let others = ["a", "c"].map(|f| dir.join(f).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(empty.unwrap().len(), 0);
+    assert_eq!(others, [true, true]);
+}
+
+#[test]
+fn test_create_files_rejects_escaping_paths() {
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld6:lengthi1e4:pathl2:..1:aeee4:name1:t12:piece lengthi4e6:pieces20:01234567890123456789e",
+    )
+    .unwrap();
+    let dir = crate::util::temp_path("escape-test");
+
+    assert!(FileMapper::new(&info).unwrap().create_files(&dir).is_err());
+    assert!(!dir.exists());
+
+    // The torrent's own directory is joined to the download dir too
+    for name in ["2:..", "4:/etc", "3:a/b", "0:"] {
+        let info = format!(
+            "d5:filesld6:lengthi1e4:pathl1:aeee4:name{}12:piece lengthi4e6:pieces20:01234567890123456789e",
+            name
+        );
+        let info = <Info as bendy::decoding::FromBencode>::from_bencode(info.as_bytes()).unwrap();
+
+        assert!(matches!(
+            FileMapper::new(&info),
+            Err(FileMapperError::UnsafeName(_))
+        ));
+    }
+}
+
+#[test]
+fn test_unsafe_name_is_rejected() {
+    // A single file's name is its path, so it can't point anywhere else
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d6:lengthi0e4:name13:../../.bashrc12:piece lengthi16384e6:pieces0:e",
+    )
+    .unwrap();
+
+    assert_eq!(
+        FileMapper::new(&info),
+        Err(FileMapperError::UnsafeName("../../.bashrc".to_string()))
+    );
+}
+
+#[test]
+fn test_check_writable() {
+    let dir = crate::util::temp_path("writable-test");
+    std::fs::create_dir(&dir).unwrap();
+    assert!(check_writable(&dir).is_ok());
+    assert!(check_writable(&dir.join("missing")).is_err());
+
+    let mut permissions = std::fs::metadata(&dir).unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&dir, permissions.clone()).unwrap();
+    let read_only = check_writable(&dir);
+
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(&dir, permissions).unwrap();
+    std::fs::remove_dir(&dir).unwrap();
+
+    assert_eq!(
+        read_only.unwrap_err().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn test_padding_files_take_room_but_are_not_created() {
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld6:lengthi3e4:pathl1:aeed4:attr1:p6:lengthi1e4:pathl4:.pad1:1eed6:lengthi4e4:pathl1:beed4:attr1:p6:lengthi1e4:pathl4:.pad1:1eed6:lengthi1e4:pathl1:ceee4:name1:t12:piece lengthi4e6:pieces60:012345678901234567890123456789012345678901234567890123456789e",
+    )
+    .unwrap();
+    let mapper = FileMapper::new(&info).unwrap();
+
+    let spans: Vec<_> = mapper
+        .files()
+        .iter()
+        .map(|f| (f.offset, f.padding, f.pieces(4)))
+        .collect();
+    assert_eq!(
+        spans,
+        vec![
+            (0, false, Some(0..=0)),
+            (3, true, Some(0..=0)),
+            (4, false, Some(1..=1)),
+            (8, true, Some(2..=2)),
+            (9, false, Some(2..=2)),
+        ]
+    );
+    assert_eq!(mapper.total_length(), 10);
+
+    let dir = crate::util::temp_path("padding-test");
+    mapper.create_files(&dir).unwrap();
+    let pad = dir.join(".pad").exists();
+    let real = ["a", "b", "c"].map(|f| dir.join(f).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!pad);
+    assert_eq!(real, [true, true, true]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_executable_and_symlink_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld4:attr1:x6:lengthi4e4:pathl3:bin3:runeed6:lengthi4e4:pathl3:lib1:aeed4:attr1:l6:lengthi0e4:pathl3:bin4:linke12:symlink pathl3:lib1:aeee4:name1:t12:piece lengthi8e6:pieces20:01234567890123456789e",
+    )
+    .unwrap();
+    let dir = crate::util::temp_path("attr-test");
+
+    FileMapper::new(&info).unwrap().create_files(&dir).unwrap();
+
+    let run = std::fs::metadata(dir.join("bin/run"))
+        .unwrap()
+        .permissions();
+    let plain = std::fs::metadata(dir.join("lib/a")).unwrap().permissions();
+    let link = std::fs::read_link(dir.join("bin/link")).unwrap();
+    let resolved = std::fs::canonicalize(dir.join("bin/link")).unwrap();
+    let expected = std::fs::canonicalize(dir.join("lib/a")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(run.mode() & 0o111, 0o111);
+    assert_eq!(plain.mode() & 0o111, 0);
+    assert_eq!(link, PathBuf::from("../lib/a"));
+    assert_eq!(resolved, expected);
+}
+
+#[test]
+fn test_symlinks_cannot_escape() {
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl2:..6:secreteee4:name1:t12:piece lengthi8e6:pieces0:e",
+    )
+    .unwrap();
+    let dir = crate::util::temp_path("symlink-escape-test");
+
+    assert!(FileMapper::new(&info).unwrap().create_files(&dir).is_err());
+    assert!(!dir.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks_cannot_be_chained_out() {
+    // p/q/s links to p, so p/q/s/evil would land in p and its `../../../x`
+    // two levels above the download dir
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld4:attr1:l6:lengthi0e4:pathl1:p1:q1:se12:symlink pathl1:peed4:attr1:l6:lengthi0e4:pathl1:p1:q1:s4:evile12:symlink pathl1:xeed6:lengthi1e4:pathl1:p1:q1:s4:evil7:payloadeee4:name1:t12:piece lengthi8e6:pieces20:01234567890123456789e",
+    )
+    .unwrap();
+    let dir = crate::util::temp_path("chained-link-test");
+
+    // Nothing may go below one of the torrent's own links, as with any file
+    assert!(matches!(
+        FileMapper::new(&info),
+        Err(FileMapperError::NestedPath(_))
+    ));
+
+    // A link left on disk is refused the same way
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld6:lengthi1e4:pathl1:p7:payloadeee4:name1:t12:piece lengthi8e6:pieces20:01234567890123456789e",
+    )
+    .unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+    std::os::unix::fs::symlink("..", dir.join("p")).unwrap();
+    let result = FileMapper::new(&info).unwrap().create_files(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_empty_symlink_path_is_rejected() {
+    let info = <Info as bendy::decoding::FromBencode>::from_bencode(
+        b"d5:filesld4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathleee4:name1:t12:piece lengthi8e6:pieces0:e",
+    )
+    .unwrap();
+    let dir = crate::util::temp_path("empty-link-test");
+
+    assert!(FileMapper::new(&info).unwrap().create_files(&dir).is_err());
+    assert!(!dir.exists());
+}
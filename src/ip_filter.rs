@@ -0,0 +1,237 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::bittorrent::Peer;
+
+#[derive(Debug, PartialEq)]
+pub enum IpFilterError {
+    Unreadable(String),
+    InvalidRange { line: usize, entry: String },
+}
+
+impl Display for IpFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use IpFilterError::*;
+
+        match self {
+            Unreadable(e) => write!(f, "IpFilterError::Unreadable: {}", e),
+            InvalidRange { line, entry } => {
+                write!(f, "IpFilterError::InvalidRange: line {}: {}", line, entry)
+            }
+        }
+    }
+}
+
+/// A CIDR range such as `10.0.0.0/8` or `2001:db8::/32`. A bare address is a
+/// range holding only itself.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IpRange {
+    V4 { network: u32, prefix: u8 },
+    V6 { network: u128, prefix: u8 },
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self, ip) {
+            (IpRange::V4 { network, prefix }, IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*ip) & mask == *network
+            }
+            (IpRange::V6 { network, prefix }, IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*ip) & mask == *network
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr.trim()).map_err(|e| e.to_string())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().map_err(|e| e.to_string())?,
+            None => max_prefix,
+        };
+
+        if prefix > max_prefix {
+            return Err(format!("prefix /{} is too long", prefix));
+        }
+
+        // Normalize the host bits away so `contains` can compare directly.
+        Ok(match addr {
+            IpAddr::V4(a) => IpRange::V4 {
+                network: u32::from(a) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0),
+                prefix,
+            },
+            IpAddr::V6(a) => IpRange::V6 {
+                network: u128::from(a) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0),
+                prefix,
+            },
+        })
+    }
+}
+
+/// Parses one range per line. Empty lines and `#` comments are skipped.
+pub fn parse_ranges(contents: &str) -> Result<Vec<IpRange>, IpFilterError> {
+    let mut ranges = vec![];
+
+    for (i, line) in contents.lines().enumerate() {
+        let entry = match line.split_once('#') {
+            Some((entry, _)) => entry,
+            None => line,
+        }
+        .trim();
+
+        if entry.is_empty() {
+            continue;
+        }
+
+        ranges.push(
+            IpRange::from_str(entry).map_err(|e| IpFilterError::InvalidRange {
+                line: i + 1,
+                entry: format!("{} ({})", entry, e),
+            })?,
+        );
+    }
+
+    Ok(ranges)
+}
+
+fn load_ranges(path: &std::path::Path) -> Result<Vec<IpRange>, IpFilterError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| IpFilterError::Unreadable(format!("{}: {}", path.display(), e)))?;
+
+    parse_ranges(&contents)
+}
+
+/// Decides which peers we are willing to connect to.
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    blocked: Vec<IpRange>,
+    allowed: Option<Vec<IpRange>>,
+}
+
+impl IpFilter {
+    pub fn new(blocked: Vec<IpRange>, allowed: Option<Vec<IpRange>>) -> Self {
+        IpFilter { blocked, allowed }
+    }
+
+    pub fn load(
+        blocklist: Option<&std::path::Path>,
+        allowlist: Option<&std::path::Path>,
+    ) -> Result<Self, IpFilterError> {
+        let blocked = match blocklist {
+            Some(path) => load_ranges(path)?,
+            None => vec![],
+        };
+
+        let allowed = match allowlist {
+            Some(path) => Some(load_ranges(path)?),
+            None => None,
+        };
+
+        Ok(IpFilter::new(blocked, allowed))
+    }
+
+    /// A peer is permitted when it is in no blocked range and, if an
+    /// allowlist was given, it is in one of its ranges.
+    pub fn permits(&self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip();
+
+        if self.blocked.iter().any(|r| r.contains(&ip)) {
+            return false;
+        }
+
+        match &self.allowed {
+            Some(allowed) => allowed.iter().any(|r| r.contains(&ip)),
+            None => true,
+        }
+    }
+
+    /// Like [`IpFilter::permits`] for a peer as trackers return it. A peer
+    /// given by host name can't be checked against ranges, so it is only
+    /// permitted when there are none.
+    pub fn permits_peer(&self, peer: &Peer) -> bool {
+        match peer.socket_addr() {
+            Some(addr) => self.permits(&addr),
+            None => self.blocked.is_empty() && self.allowed.is_none(),
+        }
+    }
+}
+
+#[test]
+fn test_ipv4_range_matching() {
+    let filter = IpFilter::new(parse_ranges("10.0.0.0/8\n192.168.1.7").unwrap(), None);
+
+    assert!(!filter.permits(&"10.1.2.3:6881".parse().unwrap()));
+    assert!(!filter.permits(&"192.168.1.7:6881".parse().unwrap()));
+    assert!(filter.permits(&"192.168.1.8:6881".parse().unwrap()));
+    assert!(filter.permits(&"11.0.0.1:6881".parse().unwrap()));
+}
+
+#[test]
+fn test_ipv6_range_matching() {
+    let filter = IpFilter::new(parse_ranges("2001:db8::/32 # documentation").unwrap(), None);
+
+    assert!(!filter.permits(&"[2001:db8::1]:6881".parse().unwrap()));
+    assert!(!filter.permits(&"[2001:db8:ffff::1]:6881".parse().unwrap()));
+    assert!(filter.permits(&"[2001:db9::1]:6881".parse().unwrap()));
+    // A v6 range never matches a v4 address
+    assert!(filter.permits(&"32.1.13.184:6881".parse().unwrap()));
+}
+
+#[test]
+fn test_allowlist_only_permits_listed_ranges() {
+    let filter = IpFilter::new(
+        parse_ranges("10.0.0.13").unwrap(),
+        Some(parse_ranges("10.0.0.0/24\n::/0").unwrap()),
+    );
+
+    assert!(filter.permits(&"10.0.0.1:6881".parse().unwrap()));
+    assert!(!filter.permits(&"10.0.0.13:6881".parse().unwrap()));
+    assert!(!filter.permits(&"10.0.1.1:6881".parse().unwrap()));
+    assert!(filter.permits(&"[::1]:6881".parse().unwrap()));
+}
+
+#[test]
+fn test_malformed_range_is_an_error() {
+    assert_eq!(
+        parse_ranges("# list\n10.0.0.0/8\n10.0.0.0/33"),
+        Err(IpFilterError::InvalidRange {
+            line: 3,
+            entry: "10.0.0.0/33 (prefix /33 is too long)".to_string()
+        })
+    );
+    assert!(parse_ranges("not an ip").is_err());
+}
+
+#[test]
+fn test_host_name_peers() {
+    let peer = Peer {
+        id: None,
+        ip: "peer.example.com".to_string(),
+        port: 6881,
+    };
+
+    assert!(IpFilter::default().permits_peer(&peer));
+    assert!(!IpFilter::new(parse_ranges("10.0.0.0/8").unwrap(), None).permits_peer(&peer));
+
+    let listed = Peer {
+        ip: "10.0.0.1".to_string(),
+        ..peer
+    };
+    assert!(IpFilter::default().permits_peer(&listed));
+    assert!(!IpFilter::new(parse_ranges("10.0.0.0/8").unwrap(), None).permits_peer(&listed));
+}
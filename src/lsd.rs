@@ -0,0 +1,245 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use rand::RngCore;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::mpsc::Sender};
+
+use crate::{
+    bittorrent::{InfoHash, Peer},
+    ip_filter::IpFilter,
+};
+
+/// BEP 14 multicast groups local peers announce to.
+pub const LSD_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 192, 152, 143)), 6771);
+pub const LSD_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)),
+    6771,
+);
+
+/// BEP 14 allows one announce per torrent every 5 minutes.
+const LSD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A `BT-SEARCH` message received from the LAN.
+#[derive(Debug, PartialEq)]
+pub struct LsdAnnounce {
+    pub port: u16,
+    /// Hex encoded info hashes the sender is sharing
+    pub info_hashes: Vec<String>,
+    /// Lets a client recognize and ignore its own announces
+    pub cookie: Option<String>,
+}
+
+pub fn announce_message(
+    group: SocketAddr,
+    port: u16,
+    info_hash: &InfoHash,
+    cookie: &str,
+) -> String {
+    format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\nInfohash: {}\r\ncookie: {}\r\n\r\n\r\n",
+        group,
+        port,
+        hex::encode(info_hash.as_bytes()),
+        cookie
+    )
+}
+
+/// Parses a `BT-SEARCH` message, `None` for anything else.
+pub fn parse_announce(message: &[u8]) -> Option<LsdAnnounce> {
+    let message = std::str::from_utf8(message).ok()?;
+    let mut lines = message.split("\r\n");
+
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = vec![];
+    let mut cookie = None;
+
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse().ok(),
+            "infohash" => info_hashes.push(value.to_ascii_lowercase()),
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Some(LsdAnnounce {
+        port: port?,
+        info_hashes,
+        cookie,
+    })
+}
+
+/// A UDP socket on `group`'s port that has joined `group`. Other clients on
+/// the machine may be listening on the same port.
+fn multicast_socket(group: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+
+    match group.ip() {
+        IpAddr::V4(ip) => {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+        }
+        IpAddr::V6(ip) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v6(&ip, 0)?;
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The peer that sent `message` from `from`, if it is another client
+/// announcing the torrent whose hex info hash is `wanted`.
+fn local_peer(message: &[u8], from: SocketAddr, wanted: &str, cookie: &str) -> Option<Peer> {
+    let found = parse_announce(message)?;
+
+    if found.cookie.as_deref() == Some(cookie) || !found.info_hashes.iter().any(|h| h == wanted) {
+        return None;
+    }
+
+    Some(Peer {
+        id: None,
+        ip: from.ip().to_canonical().to_string(),
+        port: found.port as usize,
+    })
+}
+
+/// Announces `info_hash` to `group` every [`LSD_INTERVAL`] and sends the
+/// peers on the LAN announcing the same torrent to `peers`.
+pub async fn discover(
+    group: SocketAddr,
+    info_hash: InfoHash,
+    port: u16,
+    ip_filter: Arc<IpFilter>,
+    peers: Sender<Peer>,
+    tx: Sender<String>,
+) {
+    let socket = match multicast_socket(group) {
+        Ok(s) => s,
+        Err(e) => {
+            let _ = tx
+                .send(format!(
+                    "Local peer discovery on {} unavailable: {}",
+                    group, e
+                ))
+                .await;
+            return;
+        }
+    };
+
+    let mut cookie = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut cookie);
+    let cookie = hex::encode(cookie);
+
+    let announce = announce_message(group, port, &info_hash, &cookie);
+    let wanted = hex::encode(info_hash.as_bytes());
+
+    let mut ticks = tokio::time::interval(LSD_INTERVAL);
+    let mut buffer = [0u8; 1500];
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                if let Err(e) = socket.send_to(announce.as_bytes(), group).await {
+                    let _ = tx.send(format!("Could not announce on {}: {}", group, e)).await;
+                }
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let Ok((len, from)) = received else {
+                    continue;
+                };
+
+                let Some(peer) = local_peer(&buffer[..len], from, &wanted, &cookie) else {
+                    continue;
+                };
+
+                if ip_filter.permits_peer(&peer) && peers.send(peer).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_announce_round_trip() {
+    let info_hash = InfoHash::for_tests();
+    let message = announce_message(LSD_V4, 6881, &info_hash, "c0ffee");
+
+    assert!(message.starts_with("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+    assert_eq!(
+        parse_announce(message.as_bytes()),
+        Some(LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![hex::encode(info_hash.as_bytes())],
+            cookie: Some("c0ffee".to_string()),
+        })
+    );
+}
+
+#[test]
+fn test_parse_announce_rejects_other_messages() {
+    assert_eq!(
+        parse_announce(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+        None
+    );
+    assert_eq!(
+        parse_announce(b"BT-SEARCH * HTTP/1.1\r\nInfohash: ab\r\n\r\n"),
+        None
+    );
+    assert_eq!(parse_announce(&[0xff, 0xfe]), None);
+
+    let several = b"BT-SEARCH * HTTP/1.1\r\nport: 51413\r\nINFOHASH: AB\r\nInfohash: cd\r\n\r\n";
+    assert_eq!(
+        parse_announce(several),
+        Some(LsdAnnounce {
+            port: 51413,
+            info_hashes: vec!["ab".to_string(), "cd".to_string()],
+            cookie: None,
+        })
+    );
+}
+
+#[test]
+fn test_local_peer() {
+    let info_hash = InfoHash::for_tests();
+    let wanted = hex::encode(info_hash.as_bytes());
+    let from: SocketAddr = "[::ffff:192.168.1.7]:6771".parse().unwrap();
+    let message = announce_message(LSD_V4, 51413, &info_hash, "c0ffee");
+
+    // Reached at the port it announced, not the one it sent from
+    assert_eq!(
+        local_peer(message.as_bytes(), from, &wanted, "beef"),
+        Some(Peer {
+            id: None,
+            ip: "192.168.1.7".to_string(),
+            port: 51413,
+        })
+    );
+
+    // Our own announce comes back to us
+    assert_eq!(
+        local_peer(message.as_bytes(), from, &wanted, "c0ffee"),
+        None
+    );
+
+    let other = announce_message(LSD_V4, 51413, &InfoHash::from_info_bytes(b"de"), "c0ffee");
+    assert_eq!(local_peer(other.as_bytes(), from, &wanted, "beef"), None);
+    assert_eq!(local_peer(b"garbage", from, &wanted, "beef"), None);
+}
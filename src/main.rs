@@ -1,86 +1,597 @@
-#![feature(iter_intersperse)]
-
+mod announce_cache;
 mod bittorrent;
+mod config;
+mod data_dir;
 mod download;
+mod file_mapper;
+mod ip_filter;
+mod lsd;
 mod metainfo;
+mod peer_dump;
+mod status;
 mod util;
 
+use announce_cache::AnnounceCache;
 use bendy::decoding::FromBencode;
-use chrono::DateTime;
-use clap::Parser;
-use download::{download_files, download_single_file};
+use chrono::{DateTime, Local};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use data_dir::DataDir;
+use download::{
+    DownloadOptions, TlsOptions, download_files, download_single_file, fetch_torrent_file,
+    http_client, load_ca_cert, scrape,
+};
+use file_mapper::FileMapper;
+use ip_filter::IpFilter;
 use metainfo::MetaInfoFile;
-use std::env;
+use peer_dump::PeerDump;
+use std::{
+    collections::HashSet, env, ffi::OsString, fmt::Display, path::PathBuf, process::ExitCode,
+    sync::Arc,
+};
 use tokio::fs::OpenOptions;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::sync::CancellationToken;
+use util::info;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct CliOptions {
-    /// Torrent file to donwload
+    /// Torrent file to donwload, either a path or an http(s) URL
     torrent_file_path: std::path::PathBuf,
 
     /// Show, parsed metadata from file
     #[arg(short, long)]
     verbose: bool,
 
+    /// Only print errors and what was explicitly asked for (like --info)
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Show each file's byte range and the pieces it spans
+    #[arg(long)]
+    info: bool,
+
+    /// List the torrent's files and their sizes, then exit
+    #[arg(long)]
+    list_files: bool,
+
+    /// Show dates in the local timezone instead of UTC
+    #[arg(long)]
+    local_time: bool,
+
+    /// Ask the trackers how many seeders and leechers they know, then exit
+    #[arg(long)]
+    scrape: bool,
+
     /// Sets the download dir. Defaults to $PWD
     #[arg(short, long, value_name = "DIR")]
     download_dir: Option<std::path::PathBuf>,
+
+    /// Where state kept between runs (like the peer id) lives. Defaults to $XDG_STATE_HOME/bt
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<std::path::PathBuf>,
+
+    /// TOML file setting options by their long names, like `max-announces = 8`. Defaults to config.toml in the data dir. Options on the command line win over it
+    #[arg(long, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+
+    /// File with IP ranges (CIDR or single addresses, one per line) of peers never to connect to
+    #[arg(long, value_name = "FILE")]
+    blocklist: Option<std::path::PathBuf>,
+
+    /// File with IP ranges (CIDR or single addresses, one per line) peers must be in to be connected to
+    #[arg(long, value_name = "FILE")]
+    allowlist: Option<std::path::PathBuf>,
+
+    /// File with tracker URLs, one per line, to announce to besides the torrent's own
+    #[arg(long, value_name = "FILE")]
+    trackers_file: Option<std::path::PathBuf>,
+
+    /// Port to listen for peers on. When taken, 6881-6889 are tried instead
+    #[arg(short, long, value_name = "PORT")]
+    port: Option<u16>,
+
+    /// Port to announce instead of the listen port, for when a NAT forwards a different external port to us
+    #[arg(long, value_name = "PORT")]
+    external_port: Option<u16>,
+
+    /// IP address to announce to trackers instead of the one they see us connecting from. Give it once per family (an IPv4 and an IPv6 address) to have dual-stack trackers record both
+    #[arg(long, value_name = "ADDR")]
+    announce_ip: Vec<std::net::IpAddr>,
+
+    /// Find peers on the local network through multicast (BEP 14). Ignored for private torrents
+    #[arg(long)]
+    lsd: bool,
+
+    /// Keep FILE up to date with the peers the trackers returned, one ip:port (and peer id when known) per line
+    #[arg(long, value_name = "FILE")]
+    dump_peers: Option<std::path::PathBuf>,
+
+    /// Character between announce query parameters. Only some old trackers need the non-standard ';'
+    #[arg(long, value_name = "SEP", default_value_t = '&', value_parser = parse_query_separator)]
+    query_separator: char,
+
+    /// Ask trackers for the ids of the peers they return. Otherwise `no_peer_id=1` is sent
+    #[arg(long)]
+    peer_ids: bool,
+
+    /// Stop the download after this long, like 30m, 2h or 1h30m, exiting as incomplete
+    #[arg(long, value_name = "DURATION", value_parser = util::parse_duration)]
+    max_time: Option<std::time::Duration>,
+
+    /// PEM certificate to trust for HTTPS trackers and torrent URLs, besides the system ones
+    #[arg(long, value_name = "FILE")]
+    ca_cert: Option<std::path::PathBuf>,
+
+    /// Don't verify HTTPS certificates at all. Only for debugging, anyone in between can impersonate the tracker
+    #[arg(long)]
+    insecure: bool,
+
+    /// Announce again ahead of the interval when, with peers known, nothing more was downloaded for this long, like 5m. 0 turns it off
+    #[arg(long, value_name = "DURATION", default_value = "5m", value_parser = util::parse_duration)]
+    stall_timeout: std::time::Duration,
+
+    /// Maximum number of tracker announces in flight at the same time
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    max_announces: usize,
+
+    /// Serve read-only JSON download status on PORT (GET /status and /torrents)
+    #[arg(long, value_name = "PORT")]
+    http_status: Option<u16>,
+
+    /// Address the status endpoint listens on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1")]
+    http_status_bind: std::net::IpAddr,
+}
+
+fn parse_query_separator(s: &str) -> Result<char, String> {
+    match s {
+        "&" => Ok('&'),
+        ";" => Ok(';'),
+        _ => Err("must be '&' or ';'".to_string()),
+    }
+}
+
+/// Why a run failed. Each kind exits with its own code so scripts can tell
+/// them apart (clap itself exits with 2 on bad usage).
+#[derive(Debug)]
+enum Failure {
+    /// Options that parsed but can't be used, like a malformed blocklist
+    InvalidOptions(String),
+    /// The torrent file could not be read, downloaded or parsed
+    InvalidTorrent(String),
+    /// The download's directories or files could not be set up
+    Storage(String),
+    /// The download stopped before all of it was fetched
+    Incomplete {
+        downloaded: u64,
+        total: u64,
+        interrupted: bool,
+    },
+}
+
+impl Failure {
+    fn exit_code(&self) -> ExitCode {
+        use Failure::*;
+
+        ExitCode::from(match self {
+            InvalidOptions(_) => 2,
+            InvalidTorrent(_) => 3,
+            Storage(_) => 4,
+            Incomplete { .. } => 5,
+        })
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Failure::*;
+
+        match self {
+            InvalidOptions(e) => write!(f, "Invalid options: {}", e),
+            InvalidTorrent(e) => write!(f, "Invalid torrent: {}", e),
+            Storage(e) => write!(f, "Could not set up download: {}", e),
+            Incomplete {
+                downloaded,
+                total,
+                interrupted,
+            } => write!(
+                f,
+                "Download {} ({} of {} bytes)",
+                if *interrupted {
+                    "was interrupted"
+                } else {
+                    "did not complete"
+                },
+                downloaded,
+                total
+            ),
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() {
-    let args = CliOptions::parse();
+async fn main() -> ExitCode {
+    let args = match load_options() {
+        Ok(args) => args,
+        Err(failure) => {
+            eprintln!("{}", failure);
+            return failure.exit_code();
+        }
+    };
+
+    util::set_quiet(args.quiet);
+
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("{}", failure);
+            failure.exit_code()
+        }
+    }
+}
+
+/// Reads the torrent file at `path` again on every SIGHUP and sends the
+/// trackers it now lists that aren't announced to yet, by tier. Files whose
+/// info dict changed are refused, as they describe other content.
+#[cfg(unix)]
+async fn reload_trackers_on_hangup(
+    path: PathBuf,
+    info_hash: bittorrent::InfoHash,
+    mut listed: HashSet<String>,
+    mut active: HashSet<String>,
+    tx: tokio::sync::mpsc::Sender<Vec<Vec<String>>>,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            eprintln!(
+                "Could not listen for SIGHUP, trackers won't be reloaded: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        let meta = match std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| MetaInfoFile::from_bencode(&bytes).map_err(|e| e.to_string()))
+        {
+            Ok(meta) => meta,
+            Err(e) => {
+                eprintln!("Not reloading {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        if meta.info_hash != info_hash {
+            info!(
+                "Not reloading {}: its info dict changed, which would change the content",
+                path.display()
+            );
+            continue;
+        }
+
+        let tiers = meta.tracker_tiers(&[]).unwrap_or_default();
+        let now_listed: HashSet<String> = tiers.iter().flatten().cloned().collect();
+        let dropped: Vec<&str> = listed.difference(&now_listed).map(|t| t.as_str()).collect();
+        if !dropped.is_empty() {
+            info!(
+                "No longer listed in {}, still announced to: {}",
+                path.display(),
+                dropped.join(", ")
+            );
+        }
+        listed = now_listed;
+
+        let added: Vec<Vec<String>> = tiers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .filter(|t| active.insert(t.clone()))
+                    .collect()
+            })
+            .filter(|tier: &Vec<String>| !tier.is_empty())
+            .collect();
+
+        if added.is_empty() {
+            info!("Reloaded {}, no new trackers", path.display());
+            continue;
+        }
+
+        info!(
+            "Reloaded {}, adding trackers: {}",
+            path.display(),
+            added
+                .iter()
+                .flatten()
+                .map(|t| t.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        if tx.send(added).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// There is no SIGHUP here, so the trackers are never reloaded.
+#[cfg(not(unix))]
+async fn reload_trackers_on_hangup(
+    _path: PathBuf,
+    _info_hash: bittorrent::InfoHash,
+    _listed: HashSet<String>,
+    _active: HashSet<String>,
+    _tx: tokio::sync::mpsc::Sender<Vec<Vec<String>>>,
+) {
+}
+
+/// The command line options, with those of the config file added for the
+/// ones the command line leaves out.
+fn load_options() -> Result<CliOptions, Failure> {
+    let cli: Vec<OsString> = env::args_os().collect();
+    let command = CliOptions::command();
+    let matches = command.clone().get_matches_from(&cli);
+
+    let explicit = matches.get_one::<PathBuf>("config").cloned();
+    let path = explicit.clone().or_else(|| {
+        matches
+            .get_one::<PathBuf>("data_dir")
+            .cloned()
+            .or_else(DataDir::default_path)
+            .map(|dir| dir.join(config::CONFIG_FILE_NAME))
+    });
 
-    // @TODO: persist data to disk
-    let peer_id = bittorrent::PeerId::new();
-    let bt_listen_port = 6881usize;
+    let contents = match &path {
+        Some(path) => config::read(path).map_err(|e| Failure::InvalidOptions(e.to_string()))?,
+        None => None,
+    };
 
-    println!("File path: {:?}", args.torrent_file_path);
+    let (Some(path), Some(contents)) = (path, contents) else {
+        if let Some(explicit) = explicit {
+            return Err(Failure::InvalidOptions(format!(
+                "config file {} does not exist",
+                explicit.display()
+            )));
+        }
+
+        return Ok(CliOptions::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()));
+    };
+
+    let merged = config::merge_args(&command, &matches, cli, &contents).map_err(|e| {
+        Failure::InvalidOptions(format!("could not use config {}: {}", path.display(), e))
+    })?;
+
+    Ok(CliOptions::parse_from(merged))
+}
 
-    let torrent_file = std::fs::read(args.torrent_file_path).expect("Could not read torrent file.");
+async fn run(args: CliOptions) -> Result<(), Failure> {
+    let data_dir = args
+        .data_dir
+        .clone()
+        .or_else(DataDir::default_path)
+        .and_then(|path| match DataDir::open(&path) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                eprintln!("Could not use data dir {}: {}", path.display(), e);
+                None
+            }
+        });
+
+    let peer_id = match data_dir.as_ref().map(|dir| (dir, dir.peer_id())) {
+        Some((_, Ok(id))) => id,
+        Some((dir, Err(e))) => {
+            eprintln!(
+                "Could not persist peer id in {}: {}",
+                dir.path().display(),
+                e
+            );
+            bittorrent::PeerId::new()
+        }
+        None => {
+            info!("No data dir, using a peer id for this run only");
+            bittorrent::PeerId::new()
+        }
+    };
+
+    let ip_filter = IpFilter::load(args.blocklist.as_deref(), args.allowlist.as_deref())
+        .map_err(|e| Failure::InvalidOptions(format!("could not load peer ip filter: {}", e)))?;
+
+    if args.announce_ip.iter().filter(|ip| ip.is_ipv4()).count() > 1
+        || args.announce_ip.iter().filter(|ip| ip.is_ipv6()).count() > 1
+    {
+        return Err(Failure::InvalidOptions(
+            "--announce-ip can be given once per address family".to_string(),
+        ));
+    }
+
+    info!("File path: {:?}", args.torrent_file_path);
+
+    let tls = TlsOptions {
+        ca_cert: args
+            .ca_cert
+            .as_deref()
+            .map(load_ca_cert)
+            .transpose()
+            .map_err(|e| Failure::InvalidOptions(format!("could not load CA certificate {}", e)))?,
+        insecure: args.insecure,
+    };
+
+    if tls.insecure {
+        eprintln!(
+            "WARNING: --insecure turns off HTTPS certificate verification, trackers can be impersonated"
+        );
+    }
 
-    let meta =
-        MetaInfoFile::from_bencode(&torrent_file).expect("Error parsing bencode metainfo file");
+    let client = http_client(&tls)
+        .map_err(|e| Failure::InvalidOptions(format!("could not set up HTTP client: {}", e)))?;
 
-    println!(
+    let torrent_url = args
+        .torrent_file_path
+        .to_str()
+        .and_then(|p| reqwest::Url::parse(p).ok())
+        .filter(|u| u.scheme() == "http" || u.scheme() == "https");
+
+    // Torrents fetched over http have no file to read again on SIGHUP
+    let reloadable = torrent_url.is_none();
+
+    let torrent_file = match torrent_url {
+        Some(url) => fetch_torrent_file(&client, url).await.map_err(|e| {
+            Failure::InvalidTorrent(format!("could not download torrent file: {}", e))
+        })?,
+        None => std::fs::read(&args.torrent_file_path).map_err(|e| {
+            Failure::InvalidTorrent(format!(
+                "could not read {}: {}",
+                args.torrent_file_path.display(),
+                e
+            ))
+        })?,
+    };
+
+    let meta = MetaInfoFile::from_bencode(&torrent_file).map_err(|e| {
+        Failure::InvalidTorrent(format!("error parsing bencode metainfo file: {}", e))
+    })?;
+
+    let trailing = metainfo::trailing_len(&torrent_file);
+    if trailing > 0 {
+        info!("Ignoring {} bytes after the torrent's metainfo", trailing);
+    }
+
+    info!(
         "Announces:\nannounce: {:?}\nannouce-list: {:?}",
         meta.announce, meta.announce_list
     );
 
-    if let Some(d) = meta.creation_date {
+    let extra_trackers = match &args.trackers_file {
+        Some(path) => std::fs::read_to_string(path)
+            .map(|contents| metainfo::parse_tracker_list(&contents))
+            .map_err(|e| {
+                Failure::InvalidOptions(format!("could not read {}: {}", path.display(), e))
+            })?,
+        None => vec![],
+    };
+
+    let tracker_tiers = meta.tracker_tiers(&extra_trackers);
+    // Those of the torrent file itself, to tell what a reload changed
+    let listed_trackers: HashSet<String> = meta
+        .tracker_tiers(&[])
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect();
+
+    let mapper = FileMapper::new(&meta.info).map_err(|e| Failure::InvalidTorrent(e.to_string()))?;
+
+    if args.list_files {
+        let real_files = mapper
+            .files()
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !f.padding);
+        for (i, file) in real_files.clone() {
+            println!("{:>5}  {:>15}  {}", i, file.length, file.path.join("/"));
+        }
         println!(
-            "creation date: {}",
-            DateTime::from_timestamp(d.try_into().unwrap(), 0)
-                .unwrap()
-                .format("%Y-%m-%d %H:%M:%S")
-        )
+            "{} files, {} bytes",
+            real_files.count(),
+            mapper.total_length()
+        );
+
+        return Ok(());
+    }
+
+    if args.scrape {
+        for tracker in tracker_tiers.iter().flatten().flatten() {
+            match scrape(&client, tracker, &meta.info_hash).await {
+                Ok(result) => println!("{}: {}", tracker, result),
+                Err(e) => println!("{}: {}", tracker, e),
+            }
+        }
+
+        return Ok(());
+    }
+
+    if let Some(d) = meta.creation_date {
+        // A torrent can hold any number here, not only a representable date
+        match DateTime::from_timestamp(d, 0) {
+            Some(date) if args.local_time => info!(
+                "creation date: {}",
+                date.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S %:z")
+            ),
+            Some(date) => info!("creation date: {} UTC", date.format("%Y-%m-%d %H:%M:%S")),
+            None => info!("creation date: {} (not a valid timestamp)", d),
+        }
     }
 
     if let Some(c) = meta.comment {
-        println!("Comment: {}", c);
+        info!("Comment: {}", c);
     }
 
     if let Some(cb) = meta.created_by {
-        println!("created by: {}", cb);
+        info!("created by: {}", cb);
     }
 
     if let Some(e) = meta.encoding {
-        println!("encoding: {}", e);
+        info!("encoding: {}", e);
     }
 
     if args.verbose {
         println!("Info:\n{}", meta.info);
     }
 
-    let download_dir = args
-        .download_dir
-        .or_else(|| env::current_dir().map(Some).expect("could not get pwd"))
-        .expect("could not get download dir");
+    if args.info {
+        println!(
+            "Files (piece length {}):\n{}",
+            mapper.piece_length(),
+            mapper
+        );
+    }
+
+    let download_dir = match args.download_dir {
+        Some(dir) => dir,
+        None => env::current_dir()
+            .map_err(|e| Failure::Storage(format!("could not get current dir: {}", e)))?,
+    };
+
+    file_mapper::check_writable(&download_dir).map_err(|e| {
+        Failure::Storage(format!(
+            "download directory {} is not writable: {}",
+            download_dir.display(),
+            e
+        ))
+    })?;
+
+    // Held for the whole download so the announced port stays ours
+    let listener = download::bind_listener(args.port)
+        .await
+        .map_err(|e| Failure::InvalidOptions(format!("could not listen for peers: {}", e)))?;
+    let bt_listen_port = listener
+        .local_addr()
+        .map_err(|e| Failure::InvalidOptions(format!("could not listen for peers: {}", e)))?
+        .port() as usize;
+    info!("Listening for peers on port {}", bt_listen_port);
+
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                info!("Interrupted");
+                cancel.cancel();
+            }
+        }
+    });
+
+    if let Some(limit) = args.max_time {
+        download::cancel_after(&cancel, limit);
+    }
 
     // Allocate files:
 
-    match meta.info {
+    let result = match meta.info {
         metainfo::Info::SingleFileInfo {
             name,
             piece_length,
@@ -88,24 +599,29 @@ async fn main() {
             private,
             length,
         } => {
+            if args.http_status.is_some() {
+                info!("The status endpoint is only served for multi-file torrents for now");
+            }
+
+            let file_path = download_dir.join(&name);
             let mut file_handle = OpenOptions::new()
                 .write(true)
                 .create(true)
-                .open(&name)
+                .truncate(false)
+                .open(&file_path)
                 .await
-                .expect(format!("could not open {} file", &name).as_str());
+                .map_err(|e| {
+                    Failure::Storage(format!("could not open {}: {}", file_path.display(), e))
+                })?;
 
-            let trackers = if let Some(tr) = meta.announce {
-                let mut list = vec![tr];
-
-                if let Some(trlist) = meta.announce_list {
-                    list.append(&mut trlist.clone());
-                }
-
-                Some(list)
-            } else {
-                None
-            };
+            // Whatever is there isn't the torrent's, which has no data at all
+            let existing = file_handle.metadata().await.map_or(0, |m| m.len());
+            if length == 0 && existing > 0 {
+                return Err(Failure::Storage(format!(
+                    "{} already exists and isn't empty, leaving it alone",
+                    file_path.display()
+                )));
+            }
 
             let web_seeds = if let Some(ws) = meta.url_list {
                 Some(ws)
@@ -113,7 +629,15 @@ async fn main() {
                 None
             };
 
-            download_single_file(pieces, trackers, web_seeds, &mut file_handle).await
+            download_single_file(
+                pieces,
+                tracker_tiers,
+                web_seeds,
+                &mut file_handle,
+                length,
+                cancel,
+            )
+            .await
         }
         metainfo::Info::MultiFileInfo {
             name,
@@ -122,28 +646,46 @@ async fn main() {
             private,
             files,
         } => {
-            let torrent_dir_path = download_dir.join(name);
+            let lsd = args.lsd && private != Some(true);
+            if args.lsd && !lsd {
+                info!("Not looking for local peers, the torrent is private");
+            }
+
+            let torrent_dir_path = download_dir.join(&name);
 
-            let torrent_dir_exists = std::fs::exists(&torrent_dir_path)
-                .expect("could not check if torrent directory exists");
+            let torrent_dir_exists = std::fs::exists(&torrent_dir_path).map_err(|e| {
+                Failure::Storage(format!(
+                    "could not check if {} exists: {}",
+                    torrent_dir_path.display(),
+                    e
+                ))
+            })?;
             if !torrent_dir_exists {
-                std::fs::create_dir(&torrent_dir_path).expect("could not create main dir");
+                std::fs::create_dir(&torrent_dir_path).map_err(|e| {
+                    Failure::Storage(format!(
+                        "could not create {}: {}",
+                        torrent_dir_path.display(),
+                        e
+                    ))
+                })?;
             }
 
-            let trackers: Option<Vec<String>>;
+            mapper.create_files(&torrent_dir_path).map_err(|e| {
+                Failure::Storage(format!(
+                    "could not create the files in {}: {}",
+                    torrent_dir_path.display(),
+                    e
+                ))
+            })?;
 
-            if let Some(announce) = meta.announce {
-                let mut ts = vec![announce];
-                if let Some(list) = meta.announce_list {
-                    ts.append(&mut list.clone());
-                }
-
-                trackers = Some(ts);
-            } else {
-                trackers = None;
-                println!("No trackers to download");
+            if tracker_tiers.is_none() {
+                info!("No trackers to download");
             }
 
+            let announce_cache = data_dir
+                .as_ref()
+                .map(|dir| Arc::new(AnnounceCache::load(dir, &meta.info_hash)));
+
             let web_seeds: Option<Vec<String>>;
 
             if let Some(ws) = meta.url_list {
@@ -152,7 +694,70 @@ async fn main() {
                 web_seeds = None;
             }
 
-            download_files(trackers, web_seeds, meta.info_hash, peer_id, bt_listen_port).await
+            let status_listener = match args.http_status {
+                Some(port) => Some(
+                    tokio::net::TcpListener::bind((args.http_status_bind, port))
+                        .await
+                        .map_err(|e| {
+                            Failure::InvalidOptions(format!(
+                                "could not serve status on {}:{}: {}",
+                                args.http_status_bind, port, e
+                            ))
+                        })?,
+                ),
+                None => None,
+            };
+
+            let added_tiers = reloadable.then(|| {
+                let (tx, rx) = tokio::sync::mpsc::channel(1);
+                tokio::spawn(reload_trackers_on_hangup(
+                    args.torrent_file_path.clone(),
+                    meta.info_hash.clone(),
+                    listed_trackers,
+                    tracker_tiers.iter().flatten().flatten().cloned().collect(),
+                    tx,
+                ));
+                rx
+            });
+
+            download_files(
+                client,
+                tracker_tiers,
+                web_seeds,
+                meta.info_hash,
+                peer_id,
+                files.iter().map(|f| f.length()).sum(),
+                DownloadOptions {
+                    port: args.external_port.map_or(bt_listen_port, |p| p as usize),
+                    announce_ip: args.announce_ip,
+                    query_separator: args.query_separator,
+                    peer_ids: args.peer_ids,
+                    ip_filter: Arc::new(ip_filter),
+                    max_announces: args.max_announces,
+                    lsd,
+                    cancel,
+                    peer_dump: args.dump_peers.map(|path| Arc::new(PeerDump::new(path))),
+                    announce_cache,
+                    status_listener,
+                    name,
+                    added_tiers,
+                    tls,
+                    stall_timeout: Some(args.stall_timeout).filter(|t| !t.is_zero()),
+                },
+            )
+            .await
         }
+    };
+
+    info!("{}", result.progress);
+
+    if result.completed() {
+        Ok(())
+    } else {
+        Err(Failure::Incomplete {
+            downloaded: result.progress.bytes_downloaded,
+            total: result.progress.bytes_total,
+            interrupted: result.cancelled,
+        })
     }
 }
@@ -1,17 +1,70 @@
-use std::{fmt::Display, vec};
+use std::{collections::HashSet, fmt::Display, vec};
 
-use bendy::decoding::{FromBencode, ResultExt};
+use bendy::decoding::{Decoder, FromBencode, Object, ResultExt};
 
-use crate::bittorrent::InfoHash;
+use crate::{bittorrent::InfoHash, util::info};
+
+// Bounds on how much a metainfo file may hold, far above what real torrents
+// use, so a hostile one can't make us allocate without limit
+pub const MAX_FILES: usize = 100_000;
+pub const MAX_PATH_COMPONENTS: usize = 256;
+pub const MAX_TRACKERS: usize = 1_000;
+pub const MAX_WEB_SEEDS: usize = 1_000;
+
+#[derive(Debug)]
+struct LimitExceeded {
+    what: &'static str,
+    max: usize,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "more than {} {}", self.max, self.what)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// A `pieces` string that can't be cut into whole SHA1 hashes.
+#[derive(Debug)]
+struct PartialPieceHash(usize);
+
+impl Display for PartialPieceHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes of piece hashes, not a multiple of 20", self.0)
+    }
+}
+
+impl std::error::Error for PartialPieceHash {}
+
+/// Errors once `count` entries of a list have been read and `max` allows
+/// no more.
+fn check_limit(count: usize, max: usize, what: &'static str) -> Result<(), bendy::decoding::Error> {
+    if count >= max {
+        return Err(bendy::decoding::Error::malformed_content(LimitExceeded {
+            what,
+            max,
+        }));
+    }
+
+    Ok(())
+}
 
 #[derive(PartialEq, Debug)]
 pub struct File {
     length: u64,
     path: Vec<String>,
     md5sum: Option<String>,
+    /// BEP 47 attributes, one letter each, like `p` for padding
+    attr: Option<String>,
+    /// Target of a symlink (`l`) file, relative to the torrent's root
+    symlink_path: Option<Vec<String>>,
 }
 
 impl FromBencode for File {
+    // The file dict and its path list
+    const EXPECTED_RECURSION_DEPTH: usize = 2;
+
     fn decode_bencode_object(
         object: bendy::decoding::Object,
     ) -> Result<Self, bendy::decoding::Error>
@@ -21,47 +74,94 @@ impl FromBencode for File {
         let mut path = None;
         let mut length = None;
         let mut md5sum = None;
+        let mut attr = None;
+        let mut symlink_path = None;
 
-        let mut dict = object
-            .try_into_dictionary()
-            .expect("Shoudl be a dictionary");
+        let mut dict = object.try_into_dictionary()?;
 
-        while let Some(pair) = dict.next_pair().expect("File should have pairs") {
+        while let Some(pair) = dict.next_pair()? {
             match pair {
                 (b"length", l) => {
                     length = u64::decode_bencode_object(l).context("length").map(Some)?;
                 }
                 (b"path", p) => {
-                    let mut list = p.try_into_list()?;
-                    let mut path_list = vec![];
-
-                    while let Some(list_item) = list.next_object()? {
-                        path_list.push(String::decode_bencode_object(list_item)?);
-                    }
-
-                    path = Some(path_list);
+                    path = Some(decode_path(p)?);
+                }
+                (b"symlink path", p) => {
+                    symlink_path = decode_path(p).context("symlink path").map(Some)?;
                 }
                 (b"md5sum", h) => {
                     md5sum = String::decode_bencode_object(h)
                         .context("md5sum")
                         .map(Some)?;
                 }
+                (b"attr", a) => {
+                    attr = String::decode_bencode_object(a).context("attr").map(Some)?;
+                }
                 (_, _) => {}
             }
         }
 
-        if length == None || path == None {
-            panic!("no length or path");
-        }
+        let Some(length) = length else {
+            return Err(bendy::decoding::Error::missing_field("length"));
+        };
+        let Some(path) = path else {
+            return Err(bendy::decoding::Error::missing_field("path"));
+        };
 
         Ok(File {
-            length: length.unwrap(),
-            path: path.unwrap(),
+            length,
+            path,
             md5sum,
+            attr,
+            symlink_path,
         })
     }
 }
 
+/// Decodes a file path, a list of its components.
+fn decode_path(object: Object) -> Result<Vec<String>, bendy::decoding::Error> {
+    let mut list = object.try_into_list()?;
+    let mut path = vec![];
+
+    while let Some(item) = list.next_object()? {
+        check_limit(path.len(), MAX_PATH_COMPONENTS, "path components")?;
+        path.push(String::decode_bencode_object(item)?);
+    }
+
+    Ok(path)
+}
+
+impl File {
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn path(&self) -> &Vec<String> {
+        &self.path
+    }
+
+    fn has_attr(&self, attr: char) -> bool {
+        self.attr.as_ref().is_some_and(|a| a.contains(attr))
+    }
+
+    /// Whether this is a padding file (BEP 47), there only to align the
+    /// next file to a piece boundary and never written to disk.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    /// Where the file links to when it is a symlink (BEP 47), relative to
+    /// the torrent's root.
+    pub fn symlink_path(&self) -> Option<&Vec<String>> {
+        self.symlink_path.as_ref().filter(|_| self.has_attr('l'))
+    }
+}
+
 impl Display for File {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -74,6 +174,28 @@ impl Display for File {
     }
 }
 
+/// Decodes a flag such as `private`. Torrents in the wild write it as an
+/// integer of any size and sometimes as a string, and any nonzero value sets
+/// it.
+fn decode_flag(object: Object) -> Result<bool, bendy::decoding::Error> {
+    let digits = match object {
+        Object::Integer(digits) => digits.to_string(),
+        Object::Bytes(bytes) => String::from_utf8_lossy(bytes).trim().to_string(),
+        Object::List(_) => {
+            return Err(bendy::decoding::Error::unexpected_token("Integer", "List"));
+        }
+        Object::Dict(_) => {
+            return Err(bendy::decoding::Error::unexpected_token("Integer", "Dict"));
+        }
+    };
+
+    let value: i64 = digits
+        .parse()
+        .map_err(bendy::decoding::Error::malformed_content)?;
+
+    Ok(value != 0)
+}
+
 #[derive(PartialEq, Debug)]
 
 pub enum Info {
@@ -94,12 +216,14 @@ pub enum Info {
 }
 
 impl FromBencode for Info {
+    // The info dict and its files list, with room for keys we don't know
+    // about (like v2 file trees) nesting a few levels deeper
+    const EXPECTED_RECURSION_DEPTH: usize = File::EXPECTED_RECURSION_DEPTH + 2 + 30;
+
     fn decode_bencode_object(
         object: bendy::decoding::Object,
     ) -> Result<Self, bendy::decoding::Error> {
-        let mut dict = object
-            .try_into_dictionary()
-            .expect("Info must be a dictionary");
+        let mut dict = object.try_into_dictionary()?;
 
         let mut name = None;
         let mut piece_length = None;
@@ -121,12 +245,18 @@ impl FromBencode for Info {
                         .map(Some)?
                 }
                 (b"pieces", val) => {
-                    let raw_pieces: Vec<String> = val
-                        .try_into_bytes()
-                        .expect("could not parse pieces key")
-                        .chunks(20)
-                        .map(|c| hex::encode(c))
-                        .collect();
+                    let bytes = val.try_into_bytes().context("pieces")?;
+
+                    // A short last hash could only mean a corrupt torrent
+                    if bytes.len() % 20 != 0 {
+                        return Err(bendy::decoding::Error::malformed_content(PartialPieceHash(
+                            bytes.len(),
+                        ))
+                        .context("pieces"));
+                    }
+
+                    let raw_pieces: Vec<String> =
+                        bytes.chunks(20).map(|c| hex::encode(c)).collect();
 
                     pieces = Some(raw_pieces);
                 }
@@ -136,15 +266,14 @@ impl FromBencode for Info {
                         .map(Some)?
                 }
                 (b"private", val) => {
-                    let private_val = u8::decode_bencode_object(val).context("private")?;
-
-                    private = Some(private_val == 1);
+                    private = Some(decode_flag(val).context("private")?);
                 }
                 (b"files", val) => {
-                    let mut list = val.try_into_list().expect("files must be a list");
+                    let mut list = val.try_into_list().context("files")?;
                     let mut file_list: Vec<File> = vec![];
 
                     while let Some(item) = list.next_object()? {
+                        check_limit(file_list.len(), MAX_FILES, "files").context("files")?;
                         file_list.push(File::decode_bencode_object(item).context("files")?);
                     }
 
@@ -154,22 +283,27 @@ impl FromBencode for Info {
             }
         }
 
-        if let Some(_) = length {
-            Ok(Info::SingleFileInfo {
-                name: name.expect("should have name key"),
-                piece_length: piece_length.expect("should have piece length key"),
-                pieces: pieces.expect("should have pieces key"),
-                length: length.expect("should have length key"),
+        let name = name.ok_or_else(|| bendy::decoding::Error::missing_field("name"))?;
+        let piece_length =
+            piece_length.ok_or_else(|| bendy::decoding::Error::missing_field("piece length"))?;
+        let pieces = pieces.ok_or_else(|| bendy::decoding::Error::missing_field("pieces"))?;
+
+        match (length, files) {
+            (Some(length), _) => Ok(Info::SingleFileInfo {
+                name,
+                piece_length,
+                pieces,
+                length,
                 private,
-            })
-        } else {
-            Ok(Info::MultiFileInfo {
-                name: name.expect("should have name key"),
-                piece_length: piece_length.expect("should have piece length key"),
-                pieces: pieces.expect("should have pieces key"),
-                files: files.expect("should have files key"),
+            }),
+            (None, Some(files)) => Ok(Info::MultiFileInfo {
+                name,
+                piece_length,
+                pieces,
+                files,
                 private,
-            })
+            }),
+            (None, None) => Err(bendy::decoding::Error::missing_field("length or files")),
         }
     }
 }
@@ -226,23 +360,103 @@ impl Display for Info {
 #[derive(PartialEq, Debug)]
 pub struct MetaInfoFile {
     pub announce: Option<String>,
-    pub announce_list: Option<Vec<String>>,
+    /// BEP 12 tiers, in order of preference
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: Info,
     pub created_by: Option<String>,
-    pub creation_date: Option<u64>,
+    pub creation_date: Option<i64>,
     pub comment: Option<String>,
     pub encoding: Option<String>,
     pub info_hash: InfoHash,
+    /// The `info` dictionary exactly as it appeared in the file, which is
+    /// what `info_hash` is the hash of
+    pub info_bytes: Vec<u8>,
     pub url_list: Option<Vec<String>>,
 }
 
+impl MetaInfoFile {
+    /// Every tracker of the torrent grouped in BEP 12 tiers: those of
+    /// `announce-list`, or `announce` as a tier of its own when there is no
+    /// list, as BEP 12 says to ignore it otherwise. They are followed by the
+    /// `extra` trackers each in a tier of its own so every one of them is
+    /// announced to. A tracker listed more than once is only kept where it
+    /// first appears.
+    pub fn tracker_tiers(&self, extra: &[String]) -> Option<Vec<Vec<String>>> {
+        let mut tiers: Vec<Vec<String>> = vec![];
+        let mut seen = HashSet::new();
+
+        let list: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        let torrent_tiers = if list.is_empty() {
+            self.announce.iter().map(|a| vec![a.clone()]).collect()
+        } else {
+            list
+        };
+        let extra = extra.iter().map(|t| vec![t.clone()]);
+
+        for tier in torrent_tiers.into_iter().chain(extra) {
+            let tier: Vec<String> = tier
+                .into_iter()
+                .filter(|t| seen.insert(t.clone()))
+                .collect();
+
+            if !tier.is_empty() {
+                tiers.push(tier);
+            }
+        }
+
+        if tiers.is_empty() { None } else { Some(tiers) }
+    }
+}
+
+/// How many bytes follow the top-level dictionary of a torrent file, like
+/// the padding some mail and web servers add. Decoding stops at the end of
+/// the dictionary, so they are ignored, while a truncated one still fails.
+pub fn trailing_len(bytes: &[u8]) -> usize {
+    match Decoder::new(bytes).next_object() {
+        Ok(Some(Object::Dict(dict))) => dict.into_raw().map_or(0, |raw| bytes.len() - raw.len()),
+        _ => 0,
+    }
+}
+
+/// Parses a list of tracker URLs, one per line. Empty lines and `#`
+/// comments are skipped, and so are lines that aren't http(s) URLs, with a
+/// warning. UDP trackers are among them, as there is no UDP announce yet.
+pub fn parse_tracker_list(contents: &str) -> Vec<String> {
+    let mut trackers = vec![];
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match reqwest::Url::parse(line) {
+            Ok(url) if ["http", "https"].contains(&url.scheme()) => trackers.push(line.to_string()),
+            Ok(url) => info!(
+                "Skipping tracker on line {}: unsupported scheme {}",
+                i + 1,
+                url.scheme()
+            ),
+            Err(e) => info!("Skipping tracker on line {}: {}", i + 1, e),
+        }
+    }
+
+    trackers
+}
+
 impl FromBencode for MetaInfoFile {
+    const EXPECTED_RECURSION_DEPTH: usize = Info::EXPECTED_RECURSION_DEPTH + 1;
+
     fn decode_bencode_object(
         object: bendy::decoding::Object,
     ) -> Result<Self, bendy::decoding::Error> {
-        let mut dict = object
-            .try_into_dictionary()
-            .expect("meta file must be a dict");
+        let mut dict = object.try_into_dictionary()?;
 
         let mut announce = None;
         let mut announce_list = None;
@@ -251,7 +465,7 @@ impl FromBencode for MetaInfoFile {
         let mut comment = None;
         let mut creation_date = None;
         let mut encoding = None;
-        let mut info_hash = None;
+        let mut info_bytes: Option<Vec<u8>> = None;
         let mut url_list = None;
 
         while let Some(pair) = dict.next_pair()? {
@@ -263,18 +477,39 @@ impl FromBencode for MetaInfoFile {
                 }
                 (b"announce-list", val) => {
                     if let Ok(mut list) = val.try_into_list() {
-                        let mut announce_vec: Vec<String> = vec![];
+                        let mut tiers: Vec<Vec<String>> = vec![];
+                        let mut tracker_count = 0;
 
                         while let Some(o1) = list.next_object()? {
-                            let mut l2 = o1.try_into_list()?;
-                            while let Some(o2) = l2.next_object()? {
-                                announce_vec.push(
-                                    String::decode_bencode_object(o2).context("announce-list")?,
+                            let mut tier: Vec<String> = vec![];
+                            let mut add = |tracker: Object| {
+                                check_limit(tracker_count, MAX_TRACKERS, "trackers")
+                                    .context("announce-list")?;
+                                tracker_count += 1;
+                                tier.push(
+                                    String::decode_bencode_object(tracker)
+                                        .context("announce-list")?,
                                 );
+                                Ok::<_, bendy::decoding::Error>(())
+                            };
+
+                            // Some torrents list bare trackers instead of
+                            // tiers, each is taken as a tier of its own
+                            if let Object::Bytes(_) = o1 {
+                                add(o1)?;
+                            } else {
+                                let mut l2 = o1.try_into_list()?;
+                                while let Some(o2) = l2.next_object()? {
+                                    add(o2)?;
+                                }
+                            }
+
+                            if !tier.is_empty() {
+                                tiers.push(tier);
                             }
                         }
 
-                        announce_list = Some(announce_vec);
+                        announce_list = Some(tiers);
                     }
                 }
                 (b"created by", val) => {
@@ -285,7 +520,7 @@ impl FromBencode for MetaInfoFile {
                 (b"info", val) => {
                     let raw_val = val.try_into_dictionary().context("info")?.into_raw()?;
                     info = Some(Info::from_bencode(raw_val).context("info")?);
-                    info_hash = Some(InfoHash::from_info_bytes(raw_val));
+                    info_bytes = Some(raw_val.to_vec());
                 }
                 (b"comment", val) => {
                     comment = String::decode_bencode_object(val)
@@ -293,7 +528,8 @@ impl FromBencode for MetaInfoFile {
                         .map(Some)?
                 }
                 (b"creation date", val) => {
-                    creation_date = u64::decode_bencode_object(val)
+                    // Signed, a date before 1970 is negative
+                    creation_date = i64::decode_bencode_object(val)
                         .context("creation date")
                         .map(Some)?
                 }
@@ -306,6 +542,8 @@ impl FromBencode for MetaInfoFile {
                     let mut list_decoder = val.try_into_list().context("url-list")?;
                     let mut url_vec: Vec<String> = vec![];
                     while let Some(obj) = list_decoder.next_object()? {
+                        check_limit(url_vec.len(), MAX_WEB_SEEDS, "web seeds")
+                            .context("url-list")?;
                         url_vec.push(String::decode_bencode_object(obj)?);
                     }
                     url_list = Some(url_vec);
@@ -314,16 +552,198 @@ impl FromBencode for MetaInfoFile {
             }
         }
 
+        let (Some(info), Some(info_bytes)) = (info, info_bytes) else {
+            return Err(bendy::decoding::Error::missing_field("info"));
+        };
+
         Ok(MetaInfoFile {
             announce,
             announce_list,
             created_by,
-            info: info.expect("Must have info key"),
+            info,
             comment,
             creation_date,
             encoding,
-            info_hash: info_hash.expect("should have info hash"),
+            info_hash: InfoHash::from_info_bytes(&info_bytes),
+            info_bytes,
             url_list,
         })
     }
 }
+
+#[test]
+fn test_tracker_tiers_are_deduplicated() {
+    // announce is ignored when there is an announce-list
+    let torrent = b"d8:announce3:t/x13:announce-listll3:t/ael3:t/b3:t/c3:t/bel3:t/cee4:infod6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    let meta = MetaInfoFile::from_bencode(torrent).unwrap();
+
+    assert_eq!(
+        meta.tracker_tiers(&[]),
+        Some(vec![
+            vec!["t/a".to_string()],
+            vec!["t/b".to_string(), "t/c".to_string()],
+        ])
+    );
+
+    // and used when there is none
+    let torrent = b"d8:announce3:t/x4:infod6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    let meta = MetaInfoFile::from_bencode(torrent).unwrap();
+    assert_eq!(meta.tracker_tiers(&[]), Some(vec![vec!["t/x".to_string()]]));
+}
+
+#[test]
+fn test_announce_list_with_bare_trackers() {
+    let torrent = b"d8:announce3:t/a13:announce-listl3:t/bl3:t/c3:t/de3:t/ee4:infod6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    let meta = MetaInfoFile::from_bencode(torrent).unwrap();
+
+    assert_eq!(
+        meta.announce_list,
+        Some(vec![
+            vec!["t/b".to_string()],
+            vec!["t/c".to_string(), "t/d".to_string()],
+            vec!["t/e".to_string()],
+        ])
+    );
+
+    let nested_dict = b"d8:announce3:t/a13:announce-listld1:xi1eee4:infod6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    assert!(MetaInfoFile::from_bencode(nested_dict).is_err());
+}
+
+#[test]
+fn test_extra_trackers_are_appended_once() {
+    let torrent = b"d8:announce3:t/a13:announce-listll3:t/a3:t/bee4:infod6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    let meta = MetaInfoFile::from_bencode(torrent).unwrap();
+
+    let extra = parse_tracker_list(
+        "# community trackers\nt/b\n\nudp://t.example:1337/announce\nnot a url\nftp://t.example/\nhttp://t.example/announce\n",
+    );
+    assert_eq!(extra, vec!["http://t.example/announce"]);
+
+    let mut with_repeat = extra.clone();
+    with_repeat.push("http://t.example/announce".to_string());
+
+    assert_eq!(
+        meta.tracker_tiers(&with_repeat),
+        Some(vec![
+            vec!["t/a".to_string(), "t/b".to_string()],
+            vec!["http://t.example/announce".to_string()],
+        ])
+    );
+}
+
+#[test]
+fn test_info_bytes_hash_to_info_hash() {
+    let info = b"d6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let mut torrent = b"d8:announce14:http://tracker4:info".to_vec();
+    torrent.extend_from_slice(info);
+    torrent.push(b'e');
+
+    let meta = MetaInfoFile::from_bencode(&torrent).unwrap();
+
+    assert_eq!(meta.info_bytes, info);
+    assert_eq!(InfoHash::from_info_bytes(&meta.info_bytes), meta.info_hash);
+}
+
+#[test]
+fn test_private_flag_widths() {
+    let private = |value: &str| {
+        let info = format!(
+            "d6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:private{}e",
+            value
+        );
+        match Info::from_bencode(info.as_bytes()) {
+            Ok(Info::SingleFileInfo { private, .. }) => private,
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+
+    assert_eq!(private("i1e"), Some(true));
+    assert_eq!(private("i0e"), Some(false));
+    // Doesn't fit a u8
+    assert_eq!(private("i65537e"), Some(true));
+    assert_eq!(private("1:1"), Some(true));
+    assert_eq!(private("1:0"), Some(false));
+
+    let info =
+        b"d6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privateleee";
+    assert!(Info::from_bencode(info).is_err());
+}
+
+#[test]
+fn test_huge_files_list_is_rejected() {
+    let file = b"d6:lengthi1e4:pathl1:aee";
+    let mut torrent = b"d4:infod5:filesl".to_vec();
+    for _ in 0..=MAX_FILES {
+        torrent.extend_from_slice(file);
+    }
+    torrent.extend_from_slice(b"e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee");
+
+    let err = MetaInfoFile::from_bencode(&torrent).unwrap_err();
+    assert!(
+        err.to_string().contains("more than 100000 files"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_malformed_info_is_an_error() {
+    for info in [
+        // A file without its length, or path
+        &b"d5:filesld4:pathl1:aeee4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae"
+            [..],
+        b"d5:filesld6:lengthi1eee4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        b"d5:filesli1ee4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        b"d5:files1:a4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        // Neither length nor files, or no name
+        b"d4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        b"d6:lengthi1e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        b"i1e",
+    ] {
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend_from_slice(info);
+        torrent.push(b'e');
+
+        assert!(MetaInfoFile::from_bencode(&torrent).is_err());
+    }
+
+    assert!(MetaInfoFile::from_bencode(b"d7:comment1:ce").is_err());
+    assert!(MetaInfoFile::from_bencode(b"li1ee").is_err());
+}
+
+#[test]
+fn test_deep_nesting_is_rejected() {
+    let mut torrent = b"d4:infod4:junk".to_vec();
+    torrent.extend(std::iter::repeat_n(b'l', 64));
+    torrent.extend(std::iter::repeat_n(b'e', 64));
+    torrent.extend_from_slice(
+        b"6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+    );
+
+    assert!(MetaInfoFile::from_bencode(&torrent).is_err());
+}
+
+#[test]
+fn test_trailing_bytes_are_ignored() {
+    let torrent = b"d8:announce3:t/a4:infod6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    assert_eq!(trailing_len(torrent), 0);
+
+    let mut padded = torrent.to_vec();
+    padded.extend_from_slice(b"\r\n\0\0<html>");
+    let meta = MetaInfoFile::from_bencode(&padded).unwrap();
+    assert_eq!(meta.announce, Some("t/a".to_string()));
+    assert_eq!(trailing_len(&padded), 10);
+
+    let truncated = &torrent[..torrent.len() - 1];
+    assert!(MetaInfoFile::from_bencode(truncated).is_err());
+}
+
+#[test]
+fn test_pieces_must_be_whole_hashes() {
+    let info = b"d6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces39:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaae";
+    let error = Info::from_bencode(info).unwrap_err().to_string();
+    assert!(error.contains("39 bytes of piece hashes"), "{}", error);
+
+    let info = b"d6:lengthi1e4:name1:t12:piece lengthi16384e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaae";
+    assert!(Info::from_bencode(info).is_ok());
+}
@@ -0,0 +1,87 @@
+use std::{collections::HashMap, path::PathBuf, sync::Mutex};
+
+use crate::bittorrent::Peer;
+
+/// Keeps a file listing the peers each tracker tier last returned, one
+/// `ip:port` per line followed by the hex peer id when known.
+#[derive(Debug)]
+pub struct PeerDump {
+    path: PathBuf,
+    tiers: Mutex<HashMap<usize, Vec<Peer>>>,
+}
+
+impl PeerDump {
+    pub fn new(path: PathBuf) -> Self {
+        PeerDump {
+            path,
+            tiers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the peers of `tier` and rewrites the file.
+    pub fn update(&self, tier: usize, peers: Vec<Peer>) {
+        let mut tiers = self.tiers.lock().expect("peer dump lock poisoned");
+        tiers.insert(tier, peers);
+
+        let mut indices: Vec<_> = tiers.keys().copied().collect();
+        indices.sort();
+
+        let mut lines: Vec<String> = vec![];
+        for peer in indices.iter().flat_map(|i| &tiers[i]) {
+            let address = peer
+                .socket_addr()
+                .map_or_else(|| peer.hostname(), |addr| addr.to_string());
+            let line = match &peer.id {
+                Some(id) => format!("{} {}", address, hex::encode(id.as_bytes())),
+                None => address,
+            };
+
+            if !lines.contains(&line) {
+                lines.push(line);
+            }
+        }
+
+        let contents = lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+
+        // Write then rename, so readers never see a half written list
+        let tmp_path = self.path.with_extension("tmp");
+        let saved = std::fs::write(&tmp_path, contents)
+            .and_then(|_| std::fs::rename(&tmp_path, &self.path));
+
+        if let Err(e) = saved {
+            eprintln!("Could not dump peers to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[test]
+fn test_peer_dump_lists_every_tier() {
+    let path = crate::util::temp_path("peer-dump-test");
+    let dump = PeerDump::new(path.clone());
+    let peer = |ip: &str, port| Peer {
+        id: None,
+        ip: ip.to_string(),
+        port,
+    };
+
+    dump.update(1, vec![peer("::1", 51413), peer("10.0.0.1", 6881)]);
+    dump.update(
+        0,
+        vec![Peer {
+            id: Some(crate::bittorrent::PeerId::from_bytes(
+                b"-LT0010-000000000000",
+            )),
+            ..peer("10.0.0.2", 6881)
+        }],
+    );
+    // The same peer from another tier is listed once
+    dump.update(2, vec![peer("10.0.0.1", 6881)]);
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        contents,
+        "10.0.0.2:6881 2d4c54303031302d303030303030303030303030\n[::1]:51413\n10.0.0.1:6881\n"
+    );
+}
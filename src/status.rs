@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+};
+
+use crate::bittorrent::{DownloadProgress, InfoHash};
+
+/// What the status endpoint reports about a download.
+#[derive(Debug)]
+pub struct Status {
+    name: String,
+    info_hash: InfoHash,
+    started: Instant,
+    progress: Arc<RwLock<DownloadProgress>>,
+    /// Peers each tracker tier last returned
+    tier_peers: Mutex<HashMap<usize, usize>>,
+    /// No bytes came in for a while
+    stalled: AtomicBool,
+}
+
+impl Status {
+    pub fn new(name: String, info_hash: InfoHash, progress: Arc<RwLock<DownloadProgress>>) -> Self {
+        Status {
+            name,
+            info_hash,
+            started: Instant::now(),
+            progress,
+            tier_peers: Mutex::new(HashMap::new()),
+            stalled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_tier_peers(&self, tier: usize, count: usize) {
+        self.tier_peers
+            .lock()
+            .expect("status lock poisoned")
+            .insert(tier, count);
+    }
+
+    pub fn set_stalled(&self, stalled: bool) {
+        self.stalled.store(stalled, Ordering::Relaxed);
+    }
+
+    /// Peers the tiers last returned, all together.
+    pub fn peers(&self) -> usize {
+        self.tier_peers
+            .lock()
+            .expect("status lock poisoned")
+            .values()
+            .sum()
+    }
+
+    async fn to_json(&self) -> String {
+        let peers = self.peers();
+        let progress = self.progress.read().await;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (progress.bytes_downloaded as f64 / elapsed) as u64
+        } else {
+            0
+        };
+
+        format!(
+            "{{\"name\":{},\"info_hash\":\"{}\",\"bytes_total\":{},\"bytes_downloaded\":{},\"bytes_uploaded\":{},\"peers\":{},\"rate\":{},\"stalled\":{},\"summary\":{}}}",
+            json_string(&self.name),
+            hex::encode(self.info_hash.as_bytes()),
+            progress.bytes_total,
+            progress.bytes_downloaded,
+            progress.bytes_uploaded,
+            peers,
+            rate,
+            self.stalled.load(Ordering::Relaxed),
+            json_string(&progress.to_string())
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The status line and body answering a request line like `GET /status HTTP/1.1`.
+async fn respond(request_line: &str, status: &Status) -> (&'static str, String) {
+    let mut parts = request_line.split(' ');
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    if method != "GET" {
+        return ("405 Method Not Allowed", "{\"error\":\"read only\"}".into());
+    }
+
+    match path {
+        "/status" => ("200 OK", status.to_json().await),
+        // Only the one download for now
+        "/torrents" => ("200 OK", format!("[{}]", status.to_json().await)),
+        _ => ("404 Not Found", "{\"error\":\"not found\"}".into()),
+    }
+}
+
+async fn handle(mut socket: TcpStream, status: &Status) -> std::io::Result<()> {
+    let mut request = vec![0u8; 8192];
+    let mut len = 0;
+
+    // Only the request line matters, the rest of the head is ignored
+    while !request[..len].windows(2).any(|w| w == b"\r\n") && len < request.len() {
+        let read = socket.read(&mut request[len..]).await?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+
+    let head = String::from_utf8_lossy(&request[..len]);
+    let request_line = head.lines().next().unwrap_or("");
+    let (code, body) = respond(request_line, status).await;
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+/// Answers status requests on `listener` until dropped.
+pub async fn serve(listener: TcpListener, status: Arc<Status>) {
+    loop {
+        let Ok((socket, _)) = listener.accept().await else {
+            continue;
+        };
+
+        let status = status.clone();
+        tokio::spawn(async move {
+            let _ = handle(socket, &status).await;
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_status_endpoint() {
+    let progress = Arc::new(RwLock::new(DownloadProgress {
+        bytes_total: 100,
+        bytes_downloaded: 25,
+        ..DownloadProgress::default()
+    }));
+    let status = Arc::new(Status::new(
+        "a \"name\"".to_string(),
+        InfoHash::for_tests(),
+        progress,
+    ));
+    status.set_tier_peers(0, 3);
+    status.set_tier_peers(1, 2);
+    status.set_tier_peers(0, 4);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(serve(listener, status));
+
+    let get = |path: &'static str| async move {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    let response = get("/status").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\"name\":\"a \\\"name\\\"\""));
+    assert!(response.contains("\"bytes_total\":100,\"bytes_downloaded\":25"));
+    assert!(response.contains("\"peers\":6"));
+    assert!(response.contains("\"stalled\":false"));
+    assert!(response.contains("\"summary\":\"25.0% — "));
+
+    assert!(get("/torrents").await.contains("\r\n\r\n[{\"name\""));
+    assert!(get("/peers").await.starts_with("HTTP/1.1 404"));
+
+    server.abort();
+}
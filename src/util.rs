@@ -1,6 +1,32 @@
-use std::fmt::Write;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use url_escape::encode_component_to_string;
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Silences [`info!`] output for the rest of the run.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// `println!` for informational output, which `--quiet` suppresses. Output
+/// the user explicitly asked for (like `--info`) and errors don't go
+/// through it.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::util::is_quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use info;
 
 pub fn url_encode_byte_string(data: Vec<u8>) -> String {
     let mut buffer = String::new();
@@ -17,18 +43,109 @@ pub fn url_encode_byte_string(data: Vec<u8>) -> String {
     buffer
 }
 
+/// Percent-encodes every byte but the URL unreserved characters, the way
+/// trackers expect binary values like the info hash in a query string.
+pub fn percent_encode(data: &[u8]) -> String {
+    percent_encode_case(data, false)
+}
+
+/// Like [`percent_encode`] but with upper case hex digits, which some
+/// trackers insist on.
+pub fn percent_encode_upper(data: &[u8]) -> String {
+    percent_encode_case(data, true)
+}
+
+fn percent_encode_case(data: &[u8], upper: bool) -> String {
+    let mut buffer = String::new();
+
+    for &c in data {
+        if c.is_ascii_alphanumeric() || b"-._~".contains(&c) {
+            buffer.push(c as char);
+        } else if upper {
+            write!(buffer, "%{:02X}", c).unwrap();
+        } else {
+            write!(buffer, "%{:02x}", c).unwrap();
+        }
+    }
+
+    buffer
+}
+
+/// Parses a duration like `90`, `30s`, `30m`, `2h`, `1d` or `1h30m`. A bare
+/// number is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("{:?} is not a duration like 30s, 30m, 2h or 1h30m", s);
+
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = std::mem::take(&mut digits).parse().map_err(|_| invalid())?;
+        total += Duration::from_secs(amount.checked_mul(unit).ok_or_else(invalid)?);
+    }
+
+    if !digits.is_empty() || s.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(total)
+}
+
+/// A path in the temp dir named after `name` and this process, so test runs
+/// don't trip over each other.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("bt-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+    assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+    assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+    assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(90 * 60)));
+    assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+
+    for bad in ["", "m", "1x", "1h30", "-5m", "1.5h"] {
+        assert!(parse_duration(bad).is_err(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn test_percent_encode() {
+    assert_eq!(percent_encode(b"-LT0010-ab.c_~"), "-LT0010-ab.c_~");
+    assert_eq!(
+        percent_encode(&[0x00, 0x25, 0x26, 0x3b, 0x20, 0x2b, 0xff]),
+        "%00%25%26%3b%20%2b%ff"
+    );
+    assert_eq!(percent_encode_upper(&[0x3b, 0xff, b'a']), "%3B%FFa");
+}
+
 #[test]
 fn test_encode_byte_string() {
     let bytes: [u8; 20] = [
         0x42, 0x52, 0x5b, 0xb6, 0xd3, 0xb0, 0xdc, 0x06, 0xbb, 0x78, 0xae, 0x54, 0x87, 0x33, 0xe8,
         0xfb, 0xb5, 0x54, 0x46, 0xb3,
     ];
-    let dest = url_encode_byte_string(bytes.to_vec());
-    let mut component = String::new();
-    encode_component_to_string(dest, &mut component);
 
     assert_eq!(
-        component,
-        "BR%5B%EF%BF%BD%D3%B0%EF%BF%BD%06%EF%BF%BDx%EF%BF%BDT%EF%BF%BD3%EF%BF%BD%EF%BF%BD%EF%BF%BDTF%EF%BF%BD"
+        url_encode_byte_string(bytes.to_vec()),
+        "BR[%b6%d3%b0%dc%06%bbx%aeT%873%e8%fb%b5TF%b3"
     );
 }